@@ -0,0 +1,506 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{HostPattern, POLL_INTERVAL};
+
+fn invalid_acl(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_owned())
+}
+
+// Диапазон адресов в нотации CIDR (`10.0.0.0/8`, `fd00::/8`) или один адрес.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    const fn v4(octets: [u8; 4], prefix: u8) -> Self {
+        Self {
+            addr: IpAddr::V4(Ipv4Addr::from_octets(octets)),
+            prefix,
+        }
+    }
+
+    const fn v6(segments: [u16; 8], prefix: u8) -> Self {
+        Self {
+            addr: IpAddr::V6(Ipv6Addr::from_segments(segments)),
+            prefix,
+        }
+    }
+
+    #[must_use]
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4-mapped IPv6 (::ffff:a.b.c.d) сравниваем как IPv4.
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let addr = addr
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map_err(|_| invalid_acl("bad IP address in range"))?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| invalid_acl("bad prefix length in range"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+// Хост из значения `Host` или authority: без порта и без скобок IPv6.
+#[must_use]
+pub fn authority_host(authority: &str) -> &str {
+    let authority = authority.trim();
+    if let Some(rest) = authority.strip_prefix('[') {
+        return rest.split_once(']').map_or(rest, |(h, _)| h);
+    }
+    match authority.rsplit_once(':') {
+        Some((h, p)) if !h.contains(':') && p.parse::<u16>().is_ok() => h,
+        _ => authority,
+    }
+}
+
+fn host_ip(host: &str) -> Option<IpAddr> {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+#[derive(Debug, Clone)]
+pub enum AclMatch {
+    Any,
+    // Домен и все его поддомены (`HostPattern::Domain`).
+    Domain(HostPattern),
+    #[cfg(feature = "regex")]
+    Regex(regex_lite::Regex),
+    // Только для целей, заданных IP-адресом: имена здесь не разрешаются.
+    Ip(Cidr),
+    Port(u16, u16),
+}
+
+impl AclMatch {
+    #[must_use]
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        let host = host.trim_end_matches('.');
+        match self {
+            Self::Any => true,
+            Self::Domain(pattern) => pattern.matches(host),
+            #[cfg(feature = "regex")]
+            Self::Regex(re) => re.is_match(&host.to_ascii_lowercase()),
+            Self::Ip(range) => host_ip(host).is_some_and(|ip| range.contains(ip)),
+            Self::Port(lo, hi) => (*lo..=*hi).contains(&port),
+        }
+    }
+}
+
+impl FromStr for AclMatch {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" || s.eq_ignore_ascii_case("all") {
+            return Ok(Self::Any);
+        }
+        let (kind, value) = s
+            .split_once(':')
+            .ok_or_else(|| invalid_acl("ACL match must be kind:value"))?;
+        match kind.to_ascii_lowercase().as_str() {
+            "domain" => {
+                let domain = value.trim_start_matches("*.").trim_end_matches('.');
+                if domain.is_empty() {
+                    return Err(invalid_acl("empty domain in ACL"));
+                }
+                Ok(Self::Domain(HostPattern::Domain(
+                    domain.to_ascii_lowercase(),
+                )))
+            }
+            #[cfg(feature = "regex")]
+            "regex" => regex_lite::Regex::new(value)
+                .map(Self::Regex)
+                .map_err(|e| invalid_acl(&format!("bad regex in ACL: {e}"))),
+            #[cfg(not(feature = "regex"))]
+            "regex" => Err(invalid_acl("regex ACL rules require the `regex` feature")),
+            "ip" => value.parse().map(Self::Ip),
+            "port" => {
+                let (lo, hi) = value.split_once('-').unwrap_or((value, value));
+                match (lo.parse::<u16>(), hi.parse::<u16>()) {
+                    (Ok(lo), Ok(hi)) if lo <= hi => Ok(Self::Port(lo, hi)),
+                    _ => Err(invalid_acl("bad port or port range in ACL")),
+                }
+            }
+            _ => Err(invalid_acl(
+                "ACL match must be all, domain:, regex:, ip: or port:",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+// Формат правила: `<allow|deny> <match>...`, например
+// `deny domain:example.com port:80`; все условия должны совпасть.
+#[derive(Debug, Clone)]
+pub struct AclRule {
+    pub action: AclAction,
+    pub matches: Vec<AclMatch>,
+    text: String,
+}
+
+impl AclRule {
+    #[must_use]
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        self.matches.iter().all(|m| m.matches(host, port))
+    }
+}
+
+impl FromStr for AclRule {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let action = match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("allow") => AclAction::Allow,
+            Some("deny") => AclAction::Deny,
+            _ => return Err(invalid_acl("ACL rule must start with allow or deny")),
+        };
+        let matches = words
+            .map(str::parse)
+            .collect::<io::Result<Vec<AclMatch>>>()?;
+        if matches.is_empty() {
+            return Err(invalid_acl("ACL rule needs at least one match"));
+        }
+        Ok(Self {
+            action,
+            matches,
+            text: s.split_whitespace().collect::<Vec<_>>().join(" "),
+        })
+    }
+}
+
+// Порты протоколов, где клиент сразу начинает TLS (HTTPS, SMTPS, LDAPS,
+// DNS over TLS, FTPS, IMAPS, POP3S, SIPS).
+const TLS_PORTS: &[u16] = &[443, 465, 563, 636, 853, 990, 993, 995, 5061, 8443];
+
+// Список правил доступа к целям. Решает первое совпавшее правило; если ни
+// одно не совпало, доступ разрешён. CONNECT дополнительно ограничен
+// списком портов (по умолчанию только 443).
+#[derive(Debug, Clone)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+    connect_ports: Vec<u16>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            connect_ports: vec![443],
+        }
+    }
+}
+
+impl Acl {
+    pub fn push(&mut self, rule: AclRule) {
+        self.rules.push(rule);
+    }
+
+    pub fn allow_connect_port(&mut self, port: u16) {
+        if !self.connect_ports.contains(&port) {
+            self.connect_ports.push(port);
+        }
+    }
+
+    // Разбор файла правил: одно правило на строку, `#` — комментарий.
+    pub fn parse_rules(&mut self, text: &str) -> io::Result<()> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = line.parse::<AclRule>().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("line {}: {e}", i + 1))
+            })?;
+            self.push(rule);
+        }
+        Ok(())
+    }
+
+    // `Err` содержит причину отказа для ответа 403.
+    pub fn check(&self, host: &str, port: u16) -> Result<(), String> {
+        match self.rules.iter().find(|r| r.matches(host, port)) {
+            Some(rule) if rule.action == AclAction::Deny => {
+                Err(format!("{host}:{port} denied by rule \"{}\"", rule.text))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_connect(&self, host: &str, port: u16) -> Result<(), String> {
+        if !self.connect_ports.contains(&port) {
+            return Err(format!("CONNECT to port {port} is not allowed"));
+        }
+        self.check(host, port)
+    }
+
+    // Нужна ли проверка SNI туннеля: только на портах с TLS от первого байта
+    // и только если есть что проверять — имя в CONNECT (защита от domain
+    // fronting) или правила доступа.
+    #[must_use]
+    pub fn needs_sni(&self, host: &str, port: u16) -> bool {
+        TLS_PORTS.contains(&port) && (!self.rules.is_empty() || host_ip(host).is_none())
+    }
+
+    // SNI должен совпадать с именем из CONNECT и проходить те же правила.
+    pub fn check_sni(&self, host: &str, port: u16, sni: &str) -> Result<(), String> {
+        if host_ip(host).is_none()
+            && !sni
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(host.trim_end_matches('.'))
+        {
+            return Err(format!("TLS SNI {sni} does not match CONNECT host {host}"));
+        }
+        self.check(sni, port)
+    }
+}
+
+// Фильтр адресов клиентов слушателя. Запрещающие диапазоны важнее
+// разрешающих; пустой список разрешений пропускает всех.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl ClientFilter {
+    #[must_use]
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|c| c.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip)))
+    }
+}
+
+// Внутренние и служебные сети: loopback, RFC 1918, CGNAT, link-local
+// (включая 169.254.169.254 облачных метаданных), ULA, multicast и
+// зарезервированные диапазоны. NAT64, 6to4 и IPv4-совместимые адреса
+// (`::a.b.c.d`) запрещены целиком: внутри них может быть любой IPv4.
+const PRIVATE_RANGES: &[Cidr] = &[
+    Cidr::v4([0, 0, 0, 0], 8),
+    Cidr::v4([10, 0, 0, 0], 8),
+    Cidr::v4([100, 64, 0, 0], 10),
+    Cidr::v4([127, 0, 0, 0], 8),
+    Cidr::v4([169, 254, 0, 0], 16),
+    Cidr::v4([172, 16, 0, 0], 12),
+    Cidr::v4([192, 0, 0, 0], 24),
+    Cidr::v4([192, 168, 0, 0], 16),
+    Cidr::v4([198, 18, 0, 0], 15),
+    Cidr::v4([224, 0, 0, 0], 4),
+    Cidr::v4([240, 0, 0, 0], 4),
+    Cidr::v6([0, 0, 0, 0, 0, 0, 0, 0], 96),
+    Cidr::v6([0x64, 0xff9b, 0, 0, 0, 0, 0, 0], 96),
+    Cidr::v6([0x64, 0xff9b, 1, 0, 0, 0, 0, 0], 48),
+    Cidr::v6([0x2002, 0, 0, 0, 0, 0, 0, 0], 16),
+    Cidr::v6([0xfc00, 0, 0, 0, 0, 0, 0, 0], 7),
+    Cidr::v6([0xfe80, 0, 0, 0, 0, 0, 0, 0], 10),
+    Cidr::v6([0xff00, 0, 0, 0, 0, 0, 0, 0], 8),
+];
+
+#[must_use]
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    PRIVATE_RANGES.iter().any(|r| r.contains(ip))
+}
+
+// Защита от SSRF: цели во внутренних сетях запрещены, кроме диапазонов из
+// `allow`. Имена разрешаются локально, и подключение идёт к проверенному
+// адресу, чтобы DNS не мог подменить результат проверки.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SsrfGuard {
+    pub allow: Vec<Cidr>,
+}
+
+impl SsrfGuard {
+    #[must_use]
+    pub fn blocks(&self, ip: IpAddr) -> bool {
+        is_private_ip(ip) && !self.allow.iter().any(|c| c.contains(ip))
+    }
+
+    // Адреса, к которым следует подключаться (проверены все); `Err` —
+    // причина отказа. `lookup` разрешает имя (например, через кеш DNS).
+    pub fn resolve<F>(&self, host: &str, lookup: F) -> Result<Vec<IpAddr>, String>
+    where
+        F: FnOnce(&str) -> io::Result<Vec<IpAddr>>,
+    {
+        let addrs: Vec<IpAddr> = match host_ip(host) {
+            Some(ip) => vec![ip],
+            None => lookup(host).map_err(|e| format!("cannot resolve {host}: {e}"))?,
+        };
+        if let Some(ip) = addrs.iter().find(|ip| self.blocks(**ip)) {
+            return Err(if host_ip(host).is_some() {
+                format!("{ip} is an internal address")
+            } else {
+                format!("{host} resolves to internal address {ip}")
+            });
+        }
+        if addrs.is_empty() {
+            return Err(format!("no addresses for {host}"));
+        }
+        Ok(addrs)
+    }
+}
+
+// Результат разбора начала TLS-потока клиента.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientHello {
+    // Первая TLS-запись ещё не получена целиком.
+    Incomplete,
+    NotTls,
+    Sni(Option<String>),
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    const fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|b| usize::from(b[0]))
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2)
+            .map(|b| usize::from(u16::from_be_bytes([b[0], b[1]])))
+    }
+}
+
+fn parse_sni(hello: &[u8]) -> Option<String> {
+    let mut c = Cursor(hello);
+    // Тип сообщения ClientHello = 1.
+    if c.u8()? != 1 {
+        return None;
+    }
+    c.take(3 + 2 + 32)?; // длина, версия и random
+    let n = c.u8()?;
+    c.take(n)?; // session id
+    let n = c.u16()?;
+    c.take(n)?; // cipher suites
+    let n = c.u8()?;
+    c.take(n)?; // compression methods
+    let n = c.u16()?;
+    let mut ext = Cursor(c.take(n)?);
+    while let (Some(kind), Some(n)) = (ext.u16(), ext.u16()) {
+        let data = ext.take(n)?;
+        if kind != 0 {
+            continue;
+        }
+        let mut list = Cursor(data);
+        let n = list.u16()?;
+        let mut list = Cursor(list.take(n)?);
+        while let Some(name_type) = list.u8() {
+            let n = list.u16()?;
+            let name = list.take(n)?;
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(str::to_owned);
+            }
+        }
+    }
+    None
+}
+
+// Извлекает SNI из ClientHello в первой TLS-записи. ClientHello, не
+// поместившийся в одну запись, считается сообщением без SNI.
+#[must_use]
+pub fn client_hello_sni(data: &[u8]) -> ClientHello {
+    if data.is_empty() {
+        return ClientHello::Incomplete;
+    }
+    // Тип записи handshake = 22.
+    if data[0] != 0x16 {
+        return ClientHello::NotTls;
+    }
+    let mut c = Cursor(data);
+    let Some(header) = c.take(5) else {
+        return ClientHello::Incomplete;
+    };
+    let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+    let Some(body) = c.take(len) else {
+        return ClientHello::Incomplete;
+    };
+    ClientHello::Sni(parse_sni(body))
+}
+
+// Ждёт первую TLS-запись клиента, не забирая данные из сокета. Первый
+// байт не 0x16 сразу означает `NotTls`; если клиент молчит дольше
+// `timeout` (первым говорит сервер), возвращается `Incomplete`.
+pub fn peek_client_hello(client: &TcpStream, timeout: Duration) -> io::Result<ClientHello> {
+    let saved = client.read_timeout()?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0u8; 5 + 16 * 1024];
+    let hello = loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break ClientHello::Incomplete;
+        }
+        client.set_read_timeout(Some(left))?;
+        let hello = match client.peek(&mut buf) {
+            Ok(0) => ClientHello::NotTls,
+            Ok(n) => client_hello_sni(&buf[..n]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break ClientHello::Incomplete;
+            }
+            Err(e) => return Err(e),
+        };
+        if hello != ClientHello::Incomplete {
+            break hello;
+        }
+        // Запись пришла не целиком: peek вернётся сразу, ждём остаток.
+        thread::sleep(POLL_INTERVAL.min(left));
+    };
+    client.set_read_timeout(saved)?;
+    Ok(hello)
+}
@@ -0,0 +1,250 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::base64_encode;
+
+// Буфер для тела сообщения: хранит не больше `limit` байт, но считает все.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BodyBuffer {
+    pub data: Vec<u8>,
+    pub total: u64,
+    limit: usize,
+}
+
+impl BodyBuffer {
+    #[must_use]
+    pub const fn new(limit: usize) -> Self {
+        Self {
+            data: Vec::new(),
+            total: 0,
+            limit,
+        }
+    }
+
+    #[must_use]
+    pub const fn truncated(&self) -> bool {
+        self.total > self.data.len() as u64
+    }
+}
+
+impl Write for BodyBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = self.limit.saturating_sub(self.data.len());
+        self.data.extend_from_slice(&buf[..room.min(buf.len())]);
+        self.total += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    // Установка соединения через SOCKS (и TLS, если есть).
+    pub connect: Duration,
+    // Отправка заголовка запроса.
+    pub send: Duration,
+    // Ожидание заголовка ответа.
+    pub wait: Duration,
+    // Получение тела ответа.
+    pub receive: Duration,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HttpRecord {
+    pub started: Option<SystemTime>,
+    pub client: Option<SocketAddr>,
+    pub method: String,
+    pub url: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: BodyBuffer,
+    // 0, если ответ не удалось разобрать.
+    pub status: u16,
+    pub reason: String,
+    pub response_version: String,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: BodyBuffer,
+    pub timings: Timings,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TunnelRecord {
+    pub started: Option<SystemTime>,
+    pub client: Option<SocketAddr>,
+    pub host: String,
+    pub port: u16,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureRecord {
+    Http(Box<HttpRecord>),
+    Tunnel(TunnelRecord),
+}
+
+fn json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn json_headers(out: &mut String, headers: &[(String, String)]) {
+    out.push('[');
+    for (i, (k, v)) in headers.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        json_str(out, k);
+        out.push_str(",\"value\":");
+        json_str(out, v);
+        out.push('}');
+    }
+    out.push(']');
+}
+
+// Тело в стиле HAR: текстом, если это UTF-8, иначе в base64.
+fn json_body(out: &mut String, body: &BodyBuffer) {
+    let _ = write!(out, "{{\"size\":{},\"text\":", body.total);
+    if let Ok(text) = std::str::from_utf8(&body.data) {
+        json_str(out, text);
+    } else {
+        json_str(out, &base64_encode(&body.data));
+        out.push_str(",\"encoding\":\"base64\"");
+    }
+    let _ = write!(out, ",\"truncated\":{}}}", body.truncated());
+}
+
+// Время в формате ISO 8601 (UTC) с миллисекундами.
+fn iso8601(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs();
+    let days = i64::try_from(secs / 86_400).unwrap_or(0);
+    let rem = secs % 86_400;
+    // Преобразование числа дней в дату (алгоритм Howard Hinnant).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        d.subsec_millis()
+    )
+}
+
+fn json_common(out: &mut String, started: Option<SystemTime>, client: Option<SocketAddr>) {
+    out.push_str("{\"startedDateTime\":");
+    json_str(out, &iso8601(started.unwrap_or(UNIX_EPOCH)));
+    out.push_str(",\"client\":");
+    match client {
+        Some(addr) => json_str(out, &addr.to_string()),
+        None => out.push_str("null"),
+    }
+}
+
+impl CaptureRecord {
+    // Одна строка JSON; поля HTTP-записи повторяют запись `entries` из HAR 1.2.
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let mut out = String::with_capacity(1024);
+        match self {
+            Self::Http(r) => {
+                let t = &r.timings;
+                let total = t.connect + t.send + t.wait + t.receive;
+                json_common(&mut out, r.started, r.client);
+                let _ = write!(out, ",\"time\":{}", total.as_millis());
+                out.push_str(",\"request\":{\"method\":");
+                json_str(&mut out, &r.method);
+                out.push_str(",\"url\":");
+                json_str(&mut out, &r.url);
+                out.push_str(",\"httpVersion\":\"HTTP/1.1\",\"headers\":");
+                json_headers(&mut out, &r.request_headers);
+                let _ = write!(out, ",\"bodySize\":{},\"postData\":", r.request_body.total);
+                json_body(&mut out, &r.request_body);
+                let _ = write!(out, "}},\"response\":{{\"status\":{}", r.status);
+                out.push_str(",\"statusText\":");
+                json_str(&mut out, &r.reason);
+                out.push_str(",\"httpVersion\":");
+                json_str(&mut out, &r.response_version);
+                out.push_str(",\"headers\":");
+                json_headers(&mut out, &r.response_headers);
+                let _ = write!(out, ",\"bodySize\":{},\"content\":", r.response_body.total);
+                json_body(&mut out, &r.response_body);
+                let _ = write!(
+                    out,
+                    "}},\"timings\":{{\"connect\":{},\"send\":{},\"wait\":{},\"receive\":{}}}}}",
+                    t.connect.as_millis(),
+                    t.send.as_millis(),
+                    t.wait.as_millis(),
+                    t.receive.as_millis()
+                );
+            }
+            Self::Tunnel(r) => {
+                json_common(&mut out, r.started, r.client);
+                let _ = write!(out, ",\"time\":{}", r.duration.as_millis());
+                out.push_str(",\"tunnel\":{\"host\":");
+                json_str(&mut out, &r.host);
+                let _ = write!(
+                    out,
+                    ",\"port\":{},\"bytesSent\":{},\"bytesReceived\":{}}}}}",
+                    r.port, r.bytes_sent, r.bytes_received
+                );
+            }
+        }
+        out
+    }
+}
+
+// Файл захвата в формате JSON Lines: одна запись на строку.
+#[derive(Debug)]
+pub struct CaptureLog {
+    file: Mutex<File>,
+}
+
+impl CaptureLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, record: &CaptureRecord) -> io::Result<()> {
+        let mut line = record.to_json();
+        line.push('\n');
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| io::Error::other("capture lock poisoned"))?;
+        file.write_all(line.as_bytes())
+    }
+}
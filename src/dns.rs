@@ -0,0 +1,255 @@
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
+
+use crate::resolver::DnsCache;
+
+// Задержка перед следующей попыткой подключения (RFC 8305, раздел 5).
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+// Где разрешаются имена назначения: на SOCKS-сервере (ATYP 0x03), локально
+// (на сервер уходит IPv4/IPv6), или локально с откатом на сервер, если
+// имя не удалось разрешить или ни один из адресов не подключился.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DnsMode {
+    #[default]
+    Remote,
+    Local,
+    LocalFirst,
+}
+
+impl FromStr for DnsMode {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "remote" => Ok(Self::Remote),
+            "local" => Ok(Self::Local),
+            "local-first" => Ok(Self::LocalFirst),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected remote, local or local-first",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FamilyPreference {
+    // Порядок резолвера: первым идёт семейство первого адреса.
+    #[default]
+    Any,
+    PreferIpv4,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl FromStr for FamilyPreference {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "any" => Ok(Self::Any),
+            "prefer-ipv4" => Ok(Self::PreferIpv4),
+            "prefer-ipv6" => Ok(Self::PreferIpv6),
+            "ipv4" => Ok(Self::Ipv4Only),
+            "ipv6" => Ok(Self::Ipv6Only),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected any, prefer-ipv4, prefer-ipv6, ipv4 or ipv6",
+            )),
+        }
+    }
+}
+
+impl FamilyPreference {
+    // Порядок попыток: адреса нужных семейств, чередуя IPv6 и IPv4
+    // начиная с предпочтительного (RFC 8305, раздел 4).
+    #[must_use]
+    pub fn order(self, addrs: &[IpAddr]) -> Vec<IpAddr> {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for ip in addrs {
+            let list = if ip.is_ipv4() { &mut v4 } else { &mut v6 };
+            if !list.contains(ip) {
+                list.push(*ip);
+            }
+        }
+        let v6_first = match self {
+            Self::Any => addrs.first().is_some_and(IpAddr::is_ipv6),
+            Self::PreferIpv6 => true,
+            Self::PreferIpv4 => false,
+            Self::Ipv4Only => return v4,
+            Self::Ipv6Only => return v6,
+        };
+        let (first, second) = if v6_first { (v6, v4) } else { (v4, v6) };
+        let mut out = Vec::with_capacity(first.len() + second.len());
+        let (mut a, mut b) = (first.into_iter(), second.into_iter());
+        loop {
+            match (a.next(), b.next()) {
+                (None, None) => break,
+                (x, y) => out.extend(x.into_iter().chain(y)),
+            }
+        }
+        out
+    }
+}
+
+// Разрешение имени системным резолвером.
+pub fn resolve_host(host: &str) -> io::Result<Vec<IpAddr>> {
+    Ok((host, 0).to_socket_addrs()?.map(|a| a.ip()).collect())
+}
+
+// Подключается к адресам по очереди; при `delay` следующая попытка
+// стартует, не дожидаясь предыдущей, если та не завершилась за `delay`
+// (happy eyeballs). Побеждает первое успешное подключение.
+pub fn connect_happy_eyeballs<T, F>(
+    addrs: &[IpAddr],
+    delay: Option<Duration>,
+    connect: F,
+) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: Fn(IpAddr) -> io::Result<T> + Send + Sync + 'static,
+{
+    let no_addrs = || io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to");
+    let Some(delay) = delay else {
+        let mut last = None;
+        for ip in addrs {
+            match connect(*ip) {
+                Ok(t) => return Ok(t),
+                Err(e) => last = Some(e),
+            }
+        }
+        return Err(last.unwrap_or_else(no_addrs));
+    };
+
+    let connect = Arc::new(connect);
+    let (tx, rx) = mpsc::channel();
+    let mut pending = 0usize;
+    let mut last = None;
+    for ip in addrs {
+        let (tx, connect, ip) = (tx.clone(), connect.clone(), *ip);
+        // Проигравшие попытки завершатся сами: их результат будет отброшен.
+        thread::spawn(move || {
+            let _ = tx.send(connect(ip));
+        });
+        pending += 1;
+        match rx.recv_timeout(delay) {
+            Ok(Ok(t)) => return Ok(t),
+            // Неудача запускает следующую попытку сразу.
+            Ok(Err(e)) => {
+                pending -= 1;
+                last = Some(e);
+            }
+            Err(_) => {}
+        }
+    }
+    while pending > 0 {
+        match rx.recv() {
+            Ok(Ok(t)) => return Ok(t),
+            Ok(Err(e)) => {
+                pending -= 1;
+                last = Some(e);
+            }
+            Err(_) => break,
+        }
+    }
+    Err(last.unwrap_or_else(no_addrs))
+}
+
+#[derive(Debug, Clone)]
+pub struct DnsOptions {
+    pub mode: DnsMode,
+    pub family: FamilyPreference,
+    // `None` — попытки строго по очереди.
+    pub happy_eyeballs: Option<Duration>,
+    // Без кеша каждое разрешение идёт в системный резолвер.
+    pub cache: Option<Arc<DnsCache>>,
+}
+
+impl Default for DnsOptions {
+    fn default() -> Self {
+        Self {
+            mode: DnsMode::Remote,
+            family: FamilyPreference::Any,
+            happy_eyeballs: Some(HAPPY_EYEBALLS_DELAY),
+            cache: None,
+        }
+    }
+}
+
+impl DnsOptions {
+    pub fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        self.cache
+            .as_ref()
+            .map_or_else(|| resolve_host(host), |cache| cache.lookup(host))
+    }
+
+    // `connect` получает то, что нужно передать SOCKS-серверу: исходное
+    // имя или IP-адрес в текстовом виде.
+    pub fn connect<T, F>(&self, host: &str, connect: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: Fn(&str) -> io::Result<T> + Send + Sync + 'static,
+    {
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        if literal.parse::<IpAddr>().is_ok() {
+            return connect(literal);
+        }
+        if self.mode == DnsMode::Remote {
+            return connect(host);
+        }
+        let addrs = match self.lookup(host) {
+            Ok(addrs) => self.family.order(&addrs),
+            Err(_) if self.mode == DnsMode::LocalFirst => return connect(host),
+            Err(e) => return Err(e),
+        };
+        if addrs.is_empty() {
+            if self.mode == DnsMode::LocalFirst {
+                return connect(host);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no usable addresses for {host}"),
+            ));
+        }
+        // В local-first имя уходит серверу и тогда, когда локальные адреса
+        // недоступны (например, при split-horizon DNS).
+        let connect = Arc::new(connect);
+        let by_ip = connect.clone();
+        connect_happy_eyeballs(&addrs, self.happy_eyeballs, move |ip| {
+            by_ip(&ip.to_string())
+        })
+        .or_else(|e| {
+            if self.mode == DnsMode::LocalFirst {
+                connect(host)
+            } else {
+                Err(e)
+            }
+        })
+    }
+
+    // Подключение к уже разрешённым адресам `host` (например, проверенным
+    // защитой от SSRF) с тем же выбором семейства и happy eyeballs.
+    pub fn connect_addrs<T, F>(&self, host: &str, addrs: &[IpAddr], connect: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: Fn(&str) -> io::Result<T> + Send + Sync + 'static,
+    {
+        let addrs = self.family.order(addrs);
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no usable addresses for {host}"),
+            ));
+        }
+        connect_happy_eyeballs(&addrs, self.happy_eyeballs, move |ip| {
+            connect(&ip.to_string())
+        })
+    }
+}
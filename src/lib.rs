@@ -2,10 +2,74 @@
 #![allow(clippy::missing_errors_doc)]
 #![allow(clippy::module_name_repetitions)]
 
+mod acl;
+mod capture;
+mod dns;
+#[cfg(feature = "mitm")]
+mod mitm;
+mod resolver;
+mod rules;
+mod socks_server;
+mod stream;
+#[cfg(feature = "tls")]
+mod tls;
+
+pub use acl::{
+    Acl, AclAction, AclMatch, AclRule, Cidr, ClientFilter, ClientHello, SsrfGuard, authority_host,
+    client_hello_sni, is_private_ip, peek_client_hello,
+};
+pub use capture::{BodyBuffer, CaptureLog, CaptureRecord, HttpRecord, Timings, TunnelRecord};
+pub use dns::{
+    DnsMode, DnsOptions, FamilyPreference, HAPPY_EYEBALLS_DELAY, connect_happy_eyeballs,
+    resolve_host,
+};
+#[cfg(feature = "mitm")]
+pub use mitm::{MitmCa, MitmDump};
+pub use resolver::{
+    DnsAnswer, DnsCache, DnsSource, build_dns_query, parse_dns_response, parse_hosts, query_both,
+};
+pub use rules::{Direction, HeaderAction, HeaderRule, HeaderRules, HostPattern};
+pub use socks_server::{
+    Socks5Request, socks5_accept, socks5_auth_status, socks5_read_greeting, socks5_read_request,
+    socks5_read_user_pass, socks5_reply_code, socks5_select_method, socks5_write_reply,
+};
+pub use stream::{Duplex, POLL_INTERVAL, Tap, pipe_bidirectional};
+#[cfg(feature = "tls")]
+pub use tls::{
+    TlsClientStream, TlsServerStream, TlsStream, UpstreamTlsConfig, tls_accept, tls_client_config,
+    tls_connect, tls_server_config, tls_upstream_config,
+};
+
 use std::io::{self, Read, Write};
-use std::net::TcpStream;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
 use std::time::Duration;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+}
+
+impl Scheme {
+    #[must_use]
+    pub const fn default_port(self) -> u16 {
+        match self {
+            Self::Http => 80,
+            Self::Https => 443,
+        }
+    }
+
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Http => "http",
+            Self::Https => "https",
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RequestTarget {
     Connect {
@@ -14,6 +78,7 @@ pub enum RequestTarget {
     },
     Http {
         method: String,
+        scheme: Scheme,
         host: String,
         port: u16,
         path: String,
@@ -64,22 +129,34 @@ pub fn parse_request_head(head: &[u8]) -> io::Result<RequestTarget> {
         });
     }
 
-    let uri = target;
-    let rest = uri
-        .strip_prefix("http://")
-        .or_else(|| uri.strip_prefix("https://"))
-        .unwrap_or(uri);
+    // Схема сравнивается без учёта регистра; без схемы считаем цель http.
+    // Прочие схемы (ftp://, ws://) не поддерживаются.
+    let (scheme, rest) = match target.split_once("://") {
+        Some((sc, rest)) if sc.eq_ignore_ascii_case("http") => (Scheme::Http, rest),
+        Some((sc, rest)) if sc.eq_ignore_ascii_case("https") => (Scheme::Https, rest),
+        Some((sc, _)) if is_scheme(sc) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported URL scheme {sc}"),
+            ));
+        }
+        _ => (Scheme::Http, target),
+    };
+    let default_port = scheme.default_port();
     let (authority, pathq) = rest
         .find('/')
         .map_or((rest, "/"), |idx| (&rest[..idx], &rest[idx..]));
     let (host, port) = if let Some((h, p)) = authority.rsplit_once(':') {
-        p.parse::<u16>()
-            .map_or_else(|_| (authority.to_owned(), 80), |pn| (h.to_owned(), pn))
+        p.parse::<u16>().map_or_else(
+            |_| (authority.to_owned(), default_port),
+            |pn| (h.to_owned(), pn),
+        )
     } else {
-        (authority.to_owned(), 80)
+        (authority.to_owned(), default_port)
     };
     Ok(RequestTarget::Http {
         method,
+        scheme,
         host,
         port,
         path: pathq.to_owned(),
@@ -87,178 +164,1391 @@ pub fn parse_request_head(head: &[u8]) -> io::Result<RequestTarget> {
     })
 }
 
+// Схема по RFC 3986: буква, затем буквы, цифры, `+`, `-` или `.`.
+fn is_scheme(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+}
+
+// Hop-by-hop заголовки (RFC 9110, раздел 7.6.1) относятся к одному
+// соединению и не должны пересылаться дальше.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "te",
+    "trailer",
+    "upgrade",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+];
+
+// Заголовки, задающие границы тела. Тело мы пересылаем как есть, поэтому
+// их нельзя удалять, даже если они перечислены в `Connection:`.
+const FRAMING: &[&str] = &["content-length", "transfer-encoding"];
+
+fn connection_tokens(headers: &[(String, String)]) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(k, _)| {
+            k.eq_ignore_ascii_case("connection") || k.eq_ignore_ascii_case("proxy-connection")
+        })
+        .flat_map(|(_, v)| v.split(','))
+        .map(|t| t.trim().to_ascii_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+// Запрос на смену протокола (например, WebSocket): `Connection: upgrade`
+// вместе с заголовком `Upgrade`.
+#[must_use]
+pub fn is_upgrade_request(headers: &[(String, String)]) -> bool {
+    connection_tokens(headers).iter().any(|t| t == "upgrade")
+        && headers
+            .iter()
+            .any(|(k, _)| k.eq_ignore_ascii_case("upgrade"))
+}
+
+// Удаляет hop-by-hop заголовки, включая перечисленные в `Connection:`.
+// При `tunnelling` заголовок `Upgrade` сохраняется: после смены протокола
+// соединение превращается в прозрачный туннель.
+#[must_use]
+pub fn strip_hop_by_hop(headers: &[(String, String)], tunnelling: bool) -> Vec<(String, String)> {
+    let named = connection_tokens(headers);
+    headers
+        .iter()
+        .filter(|(k, _)| {
+            let k_lower = k.to_ascii_lowercase();
+            if tunnelling && k_lower == "upgrade" {
+                return true;
+            }
+            if FRAMING.contains(&k_lower.as_str()) {
+                return true;
+            }
+            !HOP_BY_HOP.contains(&k_lower.as_str()) && !named.contains(&k_lower)
+        })
+        .cloned()
+        .collect()
+}
+
+const fn connection_header(tunnelling: bool) -> &'static str {
+    if tunnelling { "upgrade" } else { "close" }
+}
+
 pub fn write_modified_request_head<W: Write>(
     w: &mut W,
     method: &str,
     path: &str,
     headers: &[(String, String)],
 ) -> io::Result<()> {
+    let tunnelling = is_upgrade_request(headers);
     write!(w, "{method} {path} HTTP/1.1\r\n")?;
-    let mut has_connection = false;
-    for (k, v) in headers {
-        let k_lower = k.to_ascii_lowercase();
-        if k_lower == "proxy-connection" || k_lower == "proxy-authorization" {
-            continue;
+    for (k, v) in strip_hop_by_hop(headers, tunnelling) {
+        write!(w, "{k}: {v}\r\n")?;
+    }
+    // Одно соединение обслуживает один запрос, поэтому всегда просим закрыть
+    // его после ответа (кроме случая смены протокола).
+    write!(w, "Connection: {}\r\n", connection_header(tunnelling))?;
+    write!(w, "\r\n")?;
+    Ok(())
+}
+
+// Что делать с заголовками `Via` и `Forwarded`/`X-Forwarded-For`:
+// добавить свой элемент, удалить входящие или оставить как есть.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderMode {
+    Add,
+    Strip,
+    #[default]
+    Leave,
+}
+
+impl FromStr for HeaderMode {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "add" => Ok(Self::Add),
+            "strip" => Ok(Self::Strip),
+            "leave" => Ok(Self::Leave),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected add, strip or leave",
+            )),
         }
-        if k_lower == "connection" {
-            has_connection = true;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientIpHeader {
+    #[default]
+    Forwarded,
+    XForwardedFor,
+}
+
+impl FromStr for ClientIpHeader {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "forwarded" => Ok(Self::Forwarded),
+            "x-forwarded-for" => Ok(Self::XForwardedFor),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected forwarded or x-forwarded-for",
+            )),
         }
-        writeln!(w, "{k}: {v}")?;
     }
-    if !has_connection {
-        writeln!(w, "Connection: close")?;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ForwardingOptions {
+    pub via: HeaderMode,
+    pub client_ip: HeaderMode,
+    pub client_ip_header: ClientIpHeader,
+    // Протокол, по которому клиент подключён к прокси (`proto` в Forwarded).
+    pub listener: Scheme,
+}
+
+impl ForwardingOptions {
+    // Режим приватности: сервер не узнаёт ни о прокси, ни об адресе клиента.
+    #[must_use]
+    pub const fn privacy() -> Self {
+        Self {
+            via: HeaderMode::Strip,
+            client_ip: HeaderMode::Strip,
+            client_ip_header: ClientIpHeader::Forwarded,
+            listener: Scheme::Http,
+        }
     }
-    write!(w, "\r\n")?;
-    Ok(())
 }
 
-#[allow(clippy::too_many_lines)]
-pub fn socks5_connect(
-    socks_addr: &str,
-    host: &str,
-    port: u16,
-    user: Option<&str>,
-    pass: Option<&str>,
-) -> io::Result<TcpStream> {
-    let mut s = TcpStream::connect(socks_addr)?;
-    s.set_read_timeout(Some(Duration::from_secs(30)))?;
-    s.set_write_timeout(Some(Duration::from_secs(30)))?;
+const VIA_VALUE: &str = "1.1 http2socks-proxy";
+
+// Заголовки, по которым сервер может узнать адрес клиента.
+const CLIENT_IP_HEADERS: &[&str] = &[
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+];
 
-    // Приветствие клиента SOCKS5 (greeting).
-    let mut methods: Vec<u8> = vec![0x00]; // без аутентификации
-    let use_auth = user.is_some() && pass.is_some();
-    if use_auth {
-        methods.push(0x02);
+// Дописывает элемент в список значений заголовка (через запятую), либо
+// добавляет заголовок в конец, если его ещё нет.
+fn append_list_header(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    if let Some((_, v)) = headers
+        .iter_mut()
+        .rev()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+    {
+        v.push_str(", ");
+        v.push_str(value);
+    } else {
+        headers.push((name.to_owned(), value.to_owned()));
     }
-    let mut buf = Vec::with_capacity(4);
-    buf.push(0x05); // версия
-    buf.push(
-        u8::try_from(methods.len())
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many auth methods"))?,
-    );
-    buf.extend_from_slice(&methods);
-    s.write_all(&buf)?;
+}
 
-    let mut resp = [0u8; 2];
-    s.read_exact(&mut resp)?;
-    if resp[0] != 0x05 {
-        return Err(io::Error::other("SOCKS5 bad version"));
+pub fn apply_forwarding_headers(
+    headers: &mut Vec<(String, String)>,
+    opts: &ForwardingOptions,
+    client: Option<IpAddr>,
+) {
+    match opts.via {
+        HeaderMode::Add => append_list_header(headers, "Via", VIA_VALUE),
+        HeaderMode::Strip => headers.retain(|(k, _)| !k.eq_ignore_ascii_case("via")),
+        HeaderMode::Leave => {}
     }
-    match resp[1] {
-        0x00 => { /* без аутентификации */ }
-        0x02 => {
-            if !use_auth {
-                return Err(io::Error::new(
-                    io::ErrorKind::PermissionDenied,
-                    "SOCKS5 server requires auth",
-                ));
-            }
-            let (u, p) = match (user, pass) {
-                (Some(u), Some(p)) => (u.as_bytes(), p.as_bytes()),
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::PermissionDenied,
-                        "SOCKS5 auth missing creds",
-                    ));
+    match opts.client_ip {
+        HeaderMode::Add => {
+            let Some(ip) = client else { return };
+            match opts.client_ip_header {
+                ClientIpHeader::Forwarded => {
+                    // IPv6 по RFC 7239 записывается в кавычках и скобках.
+                    let node = match ip {
+                        IpAddr::V4(v4) => v4.to_string(),
+                        IpAddr::V6(v6) => format!("\"[{v6}]\""),
+                    };
+                    let proto = opts.listener.as_str();
+                    append_list_header(headers, "Forwarded", &format!("for={node};proto={proto}"));
+                }
+                ClientIpHeader::XForwardedFor => {
+                    append_list_header(headers, "X-Forwarded-For", &ip.to_string());
                 }
-            };
-            if u.len() > 255 || p.len() > 255 {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "username/password too long",
-                ));
             }
-            let mut a = Vec::with_capacity(3 + u.len() + p.len());
-            a.push(0x01); // версия подпроцедуры аутентификации
-            let ulen = u8::try_from(u.len())
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "username too long"))?;
-            a.push(ulen);
-            a.extend_from_slice(u);
-            let plen = u8::try_from(p.len())
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "password too long"))?;
-            a.push(plen);
-            a.extend_from_slice(p);
-            s.write_all(&a)?;
-            let mut ar = [0u8; 2];
-            s.read_exact(&mut ar)?;
-            if ar[1] != 0x00 {
-                return Err(io::Error::new(
-                    io::ErrorKind::PermissionDenied,
-                    "SOCKS5 auth failed",
-                ));
+        }
+        HeaderMode::Strip => headers.retain(|(k, _)| {
+            !CLIENT_IP_HEADERS
+                .iter()
+                .any(|name| k.eq_ignore_ascii_case(name))
+        }),
+        HeaderMode::Leave => {}
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+pub fn parse_response_head(head: &[u8]) -> io::Result<ResponseHead> {
+    let s = std::str::from_utf8(head)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid utf8 in response"))?;
+    let mut lines = s.split("\r\n");
+    let status_line = lines
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty response"))?;
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts
+        .next()
+        .filter(|v| v.starts_with("HTTP/"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad status line"))?
+        .to_owned();
+    let status = parts
+        .next()
+        .and_then(|c| c.parse::<u16>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad status code"))?;
+    let reason = parts.next().unwrap_or("").to_owned();
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            headers.push((k.trim().to_string(), v.trim().to_string()));
+        }
+    }
+    Ok(ResponseHead {
+        version,
+        status,
+        reason,
+        headers,
+    })
+}
+
+// Пишет ответ клиенту по тем же правилам, что и запрос: hop-by-hop
+// заголовки удаляются, `Connection` выставляется прокси.
+pub fn write_modified_response_head<W: Write>(
+    w: &mut W,
+    resp: &ResponseHead,
+    upgrade_requested: bool,
+) -> io::Result<()> {
+    let tunnelling = upgrade_requested && resp.status == 101;
+    write!(w, "{} {} {}\r\n", resp.version, resp.status, resp.reason)?;
+    for (k, v) in strip_hop_by_hop(&resp.headers, tunnelling) {
+        write!(w, "{k}: {v}\r\n")?;
+    }
+    write!(w, "Connection: {}\r\n", connection_header(tunnelling))?;
+    write!(w, "\r\n")?;
+    Ok(())
+}
+
+// Промежуточный ответ 1xx: соединение продолжается, поэтому hop-by-hop
+// заголовки удаляются, а `Connection: close` не добавляется.
+pub fn write_interim_response_head<W: Write>(w: &mut W, resp: &ResponseHead) -> io::Result<()> {
+    write!(w, "{} {} {}\r\n", resp.version, resp.status, resp.reason)?;
+    for (k, v) in strip_hop_by_hop(&resp.headers, false) {
+        write!(w, "{k}: {v}\r\n")?;
+    }
+    write!(w, "\r\n")?;
+    Ok(())
+}
+
+// Читает ответ до окончательного заголовка. Промежуточные 1xx (кроме 101)
+// после `rewrite` сразу уходят клиенту; `buf` в итоге начинается с
+// окончательного заголовка. `None` — поток кончился раньше или заголовок не
+// разобрать, тогда `buf` передаётся клиенту как есть.
+pub fn read_final_response_head<R: Read, W: Write>(
+    upstream: &mut R,
+    client: &mut W,
+    buf: &mut Vec<u8>,
+    mut rewrite: impl FnMut(&mut ResponseHead),
+) -> io::Result<Option<ResponseHead>> {
+    loop {
+        let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+            if read_until_double_crlf(upstream, buf)? == 0 {
+                return Ok(None);
+            }
+            continue;
+        };
+        let end = pos + 4;
+        let Ok(mut resp) = parse_response_head(&buf[..end]) else {
+            return Ok(None);
+        };
+        rewrite(&mut resp);
+        if resp.status / 100 != 1 || resp.status == 101 {
+            return Ok(Some(resp));
+        }
+        write_interim_response_head(client, &resp)?;
+        client.flush()?;
+        buf.drain(..end);
+    }
+}
+
+// Короткий ответ прокси с текстом ошибки; соединение после него закрывается.
+pub fn write_error_response<W: Write>(
+    w: &mut W,
+    status: u16,
+    reason: &str,
+    message: &str,
+) -> io::Result<()> {
+    write!(
+        w,
+        "HTTP/1.1 {status} {reason}\r\nProxy-Agent: http2socks-proxy\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{message}\n",
+        message.len() + 1
+    )?;
+    w.flush()
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Стандартный base64 (RFC 4648) с выравниванием `=`.
+#[must_use]
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
             }
         }
-        0xFF => {
+    }
+    out
+}
+
+pub fn read_until_double_crlf<R: Read>(stream: &mut R, buf: &mut Vec<u8>) -> io::Result<usize> {
+    let mut tmp = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut tmp)?;
+        if n == 0 {
+            return Ok(0);
+        }
+        buf.extend_from_slice(&tmp[..n]);
+        if buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            return Ok(buf.len());
+        }
+        // Ограничиваем размер заголовков, чтобы не переполнить буфер.
+        if buf.len() > 64 * 1024 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Headers too large",
+            ));
+        }
+    }
+}
+
+// Байты, прочитанные вместе с заголовком после CRLFCRLF (начало тела).
+#[must_use]
+pub fn body_after_head(buf: &[u8]) -> &[u8] {
+    buf.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(&[], |pos| &buf[pos + 4..])
+}
+
+// Сериализация заголовка запроса в том виде, в котором его понимает
+// `parse_request_head`: порядок и регистр заголовков сохраняются как есть.
+pub fn write_request_head<W: Write>(w: &mut W, req: &RequestTarget) -> io::Result<()> {
+    match req {
+        RequestTarget::Connect { host, port } => {
+            write!(w, "CONNECT {host}:{port} HTTP/1.1\r\n\r\n")?;
+        }
+        RequestTarget::Http {
+            method,
+            scheme,
+            host,
+            port,
+            path,
+            headers,
+        } => {
+            let scheme = scheme.as_str();
+            write!(w, "{method} {scheme}://{host}:{port}{path} HTTP/1.1\r\n")?;
+            for (k, v) in headers {
+                write!(w, "{k}: {v}\r\n")?;
+            }
+            write!(w, "\r\n")?;
+        }
+    }
+    Ok(())
+}
+
+#[must_use]
+pub fn serialize_request_head(req: &RequestTarget) -> Vec<u8> {
+    let mut out = Vec::with_capacity(256);
+    // Запись в Vec не может завершиться ошибкой.
+    let _ = write_request_head(&mut out, req);
+    out
+}
+
+// Адрес в запросах и ответах SOCKS5: IP или доменное имя (ATYP 0x03).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    #[must_use]
+    pub fn new(host: &str, port: u16) -> Self {
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        literal.parse::<IpAddr>().map_or_else(
+            |_| Self::Domain(host.to_owned(), port),
+            |ip| Self::Ip(SocketAddr::new(ip, port)),
+        )
+    }
+
+    #[must_use]
+    pub const fn port(&self) -> u16 {
+        match self {
+            Self::Ip(addr) => addr.port(),
+            Self::Domain(_, port) => *port,
+        }
+    }
+}
+
+impl std::fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{addr}"),
+            Self::Domain(host, port) => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+// ATYP, адрес и порт в формате RFC 1928.
+fn push_socks5_addr(buf: &mut Vec<u8>, target: &TargetAddr) -> io::Result<()> {
+    match target {
+        TargetAddr::Ip(SocketAddr::V4(a)) => {
+            buf.push(0x01); // IPv4-адрес
+            buf.extend_from_slice(&a.ip().octets());
+        }
+        TargetAddr::Ip(SocketAddr::V6(a)) => {
+            buf.push(0x04); // IPv6-адрес
+            buf.extend_from_slice(&a.ip().octets());
+        }
+        TargetAddr::Domain(host, _) => {
+            let hlen = u8::try_from(host.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "hostname too long"))?;
+            buf.push(0x03); // доменное имя
+            buf.push(hlen);
+            buf.extend_from_slice(host.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&target.port().to_be_bytes());
+    Ok(())
+}
+
+fn read_socks5_addr<R: Read>(r: &mut R, atyp: u8) -> io::Result<TargetAddr> {
+    let mut port = [0u8; 2];
+    let target = match atyp {
+        0x01 => {
+            let mut a = [0u8; 4];
+            r.read_exact(&mut a)?;
+            r.read_exact(&mut port)?;
+            TargetAddr::Ip(SocketAddr::new(IpAddr::from(a), u16::from_be_bytes(port)))
+        }
+        0x03 => {
+            let mut l = [0u8; 1];
+            r.read_exact(&mut l)?;
+            let mut n = vec![0u8; usize::from(l[0])];
+            r.read_exact(&mut n)?;
+            r.read_exact(&mut port)?;
+            let host = String::from_utf8(n)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad SOCKS5 hostname"))?;
+            TargetAddr::Domain(host, u16::from_be_bytes(port))
+        }
+        0x04 => {
+            let mut a = [0u8; 16];
+            r.read_exact(&mut a)?;
+            r.read_exact(&mut port)?;
+            TargetAddr::Ip(SocketAddr::new(IpAddr::from(a), u16::from_be_bytes(port)))
+        }
+        _ => return Err(io::Error::other("SOCKS5 bad ATYP in reply")),
+    };
+    Ok(target)
+}
+
+// Поток для подпереговоров аутентификации (нужен для `dyn Socks5Auth`).
+pub trait ReadWrite: Read + Write {}
+
+impl<T: Read + Write + ?Sized> ReadWrite for T {}
+
+// Метод аутентификации SOCKS5 (RFC 1928, раздел 3): байт в приветствии
+// и подпереговоры после того, как сервер выбрал этот метод.
+pub trait Socks5Auth {
+    fn method(&self) -> u8;
+    fn authenticate(&self, stream: &mut dyn ReadWrite) -> io::Result<()>;
+}
+
+// 0x00: без аутентификации.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoAuth;
+
+impl Socks5Auth for NoAuth {
+    fn method(&self) -> u8 {
+        0x00
+    }
+
+    fn authenticate(&self, _stream: &mut dyn ReadWrite) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// 0x02: имя и пароль (RFC 1929).
+#[derive(Debug, Clone)]
+pub struct UserPassAuth {
+    pub user: String,
+    pub pass: String,
+}
+
+impl Socks5Auth for UserPassAuth {
+    fn method(&self) -> u8 {
+        0x02
+    }
+
+    fn authenticate(&self, stream: &mut dyn ReadWrite) -> io::Result<()> {
+        let (u, p) = (self.user.as_bytes(), self.pass.as_bytes());
+        let ulen = u8::try_from(u.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "username too long"))?;
+        let plen = u8::try_from(p.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "password too long"))?;
+        let mut a = Vec::with_capacity(3 + u.len() + p.len());
+        a.push(0x01); // версия подпроцедуры аутентификации
+        a.push(ulen);
+        a.extend_from_slice(u);
+        a.push(plen);
+        a.extend_from_slice(p);
+        stream.write_all(&a)?;
+        stream.flush()?;
+        let mut ar = [0u8; 2];
+        stream.read_exact(&mut ar)?;
+        if ar[1] != 0x00 {
             return Err(io::Error::new(
                 io::ErrorKind::PermissionDenied,
-                "SOCKS5 no acceptable auth",
+                "SOCKS5 auth failed",
             ));
         }
-        _ => return Err(io::Error::other("SOCKS5 unsupported method")),
+        Ok(())
     }
+}
 
-    // Запрос CONNECT к целевому хосту через SOCKS5
-    let mut req = Vec::with_capacity(512);
-    req.push(0x05); // версия
-    req.push(0x01); // команда CONNECT
-    req.push(0x00); // зарезервировано
+// Частные методы 0x80–0xFE (RFC 1928, «reserved for private methods»):
+// подпереговоры выполняет переданная функция.
+pub struct CustomAuth<F> {
+    method: u8,
+    negotiate: F,
+}
 
-    // Адрес назначения
-    if let Ok(addr) = host.parse::<std::net::Ipv4Addr>() {
-        req.push(0x01); // IPv4-адрес
-        req.extend_from_slice(&addr.octets());
-    } else if let Ok(addr6) = host.parse::<std::net::Ipv6Addr>() {
-        req.push(0x04); // IPv6-адрес
-        req.extend_from_slice(&addr6.octets());
-    } else {
-        let hb = host.as_bytes();
-        if hb.len() > 255 {
+impl<F: Fn(&mut dyn ReadWrite) -> io::Result<()>> CustomAuth<F> {
+    pub fn new(method: u8, negotiate: F) -> io::Result<Self> {
+        if !(0x80..=0xFE).contains(&method) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                "hostname too long",
+                "private SOCKS5 methods are 0x80-0xFE",
             ));
         }
-        req.push(0x03); // доменное имя
-        let hlen = u8::try_from(hb.len())
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "hostname too long"))?;
-        req.push(hlen);
-        req.extend_from_slice(hb);
+        Ok(Self { method, negotiate })
     }
-    req.extend_from_slice(&port.to_be_bytes());
-    s.write_all(&req)?;
+}
+
+impl<F: Fn(&mut dyn ReadWrite) -> io::Result<()>> Socks5Auth for CustomAuth<F> {
+    fn method(&self) -> u8 {
+        self.method
+    }
+
+    fn authenticate(&self, stream: &mut dyn ReadWrite) -> io::Result<()> {
+        (self.negotiate)(stream)
+    }
+}
 
-    // Ответ: VER REP RSV ATYP BND.ADDR BND.PORT
+// Приветствие с методами в порядке предпочтения и подпереговоры
+// выбранного сервером метода.
+fn socks5_negotiate<S: Read + Write>(s: &mut S, auth: &[&dyn Socks5Auth]) -> io::Result<()> {
+    let count = u8::try_from(auth.len())
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "1-255 auth methods needed"))?;
+    let mut buf = Vec::with_capacity(2 + auth.len());
+    buf.push(0x05); // версия
+    buf.push(count);
+    buf.extend(auth.iter().map(|a| a.method()));
+    s.write_all(&buf)?;
+    s.flush()?;
+
+    let mut resp = [0u8; 2];
+    s.read_exact(&mut resp)?;
+    if resp[0] != 0x05 {
+        return Err(io::Error::other("SOCKS5 bad version"));
+    }
+    match (resp[1], auth.iter().find(|a| a.method() == resp[1])) {
+        (_, Some(method)) => method.authenticate(s),
+        (0xFF, None) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 no acceptable auth",
+        )),
+        (0x02, None) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 server requires auth",
+        )),
+        (method, None) => Err(io::Error::other(format!(
+            "SOCKS5 unsupported method 0x{method:02x}"
+        ))),
+    }
+}
+
+// Без аутентификации и, если заданы имя и пароль, по RFC 1929.
+fn socks5_handshake<S: Read + Write>(
+    s: &mut S,
+    user: Option<&str>,
+    pass: Option<&str>,
+) -> io::Result<()> {
+    let user_pass = match (user, pass) {
+        (Some(user), Some(pass)) => Some(UserPassAuth {
+            user: user.to_owned(),
+            pass: pass.to_owned(),
+        }),
+        _ => None,
+    };
+    match &user_pass {
+        Some(user_pass) => socks5_negotiate(s, &[&NoAuth, user_pass]),
+        None => socks5_negotiate(s, &[&NoAuth]),
+    }
+}
+
+// Таймаут чтения и записи для соединений с вышестоящими серверами.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn upstream_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+    let s = TcpStream::connect(addr)?;
+    s.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+    s.set_write_timeout(Some(UPSTREAM_TIMEOUT))?;
+    Ok(s)
+}
+
+const fn socks5_command_name(cmd: u8) -> &'static str {
+    match cmd {
+        0x01 => "connect",
+        0x02 => "bind",
+        _ => "udp associate",
+    }
+}
+
+// Ответ: VER REP RSV ATYP BND.ADDR BND.PORT
+fn read_socks5_reply<R: Read>(r: &mut R, cmd: u8) -> io::Result<TargetAddr> {
     let mut hdr = [0u8; 4];
-    s.read_exact(&mut hdr)?;
+    r.read_exact(&mut hdr)?;
     if hdr[0] != 0x05 {
         return Err(io::Error::other("SOCKS5 bad version in reply"));
     }
     if hdr[1] != 0x00 {
         let reply_code = hdr[1];
         return Err(io::Error::other(format!(
-            "SOCKS5 connect failed: 0x{reply_code:02x}"
+            "SOCKS5 {} failed: 0x{reply_code:02x}",
+            socks5_command_name(cmd)
         )));
     }
-    // Считываем поле адреса согласно типу ATYP
-    match hdr[3] {
-        0x01 => {
-            let mut skip = [0u8; 4];
-            s.read_exact(&mut skip)?;
+    read_socks5_addr(r, hdr[3])
+}
+
+// Отправляет команду (CONNECT, BIND, UDP ASSOCIATE) и читает первый ответ.
+fn socks5_request<S: Read + Write>(
+    s: &mut S,
+    cmd: u8,
+    target: &TargetAddr,
+) -> io::Result<TargetAddr> {
+    let mut req = Vec::with_capacity(512);
+    req.push(0x05); // версия
+    req.push(cmd);
+    req.push(0x00); // зарезервировано
+    push_socks5_addr(&mut req, target)?;
+    s.write_all(&req)?;
+    s.flush()?;
+    read_socks5_reply(s, cmd)
+}
+
+fn socks5_open(socks_addr: &str, user: Option<&str>, pass: Option<&str>) -> io::Result<TcpStream> {
+    let mut s = upstream_tcp(socks_addr)?;
+    socks5_handshake(&mut s, user, pass)?;
+    Ok(s)
+}
+
+pub fn socks5_connect(
+    socks_addr: &str,
+    host: &str,
+    port: u16,
+    user: Option<&str>,
+    pass: Option<&str>,
+) -> io::Result<TcpStream> {
+    let s = upstream_tcp(socks_addr)?;
+    socks5_connect_over(s, host, port, user, pass)
+}
+
+// CONNECT поверх любого потока: туннеля предыдущего сервера в цепочке,
+// TLS, Unix-сокета. Таймауты — забота вызывающего.
+pub fn socks5_connect_over<S: Read + Write>(
+    mut stream: S,
+    host: &str,
+    port: u16,
+    user: Option<&str>,
+    pass: Option<&str>,
+) -> io::Result<S> {
+    socks5_handshake(&mut stream, user, pass)?;
+    socks5_request(&mut stream, 0x01, &TargetAddr::new(host, port))?;
+    Ok(stream)
+}
+
+// CONNECT с произвольным набором методов аутентификации, в порядке
+// предпочтения.
+pub fn socks5_connect_with<S: Read + Write>(
+    mut stream: S,
+    host: &str,
+    port: u16,
+    auth: &[&dyn Socks5Auth],
+) -> io::Result<S> {
+    socks5_negotiate(&mut stream, auth)?;
+    socks5_request(&mut stream, 0x01, &TargetAddr::new(host, port))?;
+    Ok(stream)
+}
+
+// SOCKS4: только IPv4-адреса назначения. С `socks4a` имя передаётся
+// серверу (расширение 4a: DSTIP 0.0.0.1 и имя после USERID).
+pub fn socks4_connect(
+    socks_addr: &str,
+    host: &str,
+    port: u16,
+    user_id: Option<&str>,
+    socks4a: bool,
+) -> io::Result<TcpStream> {
+    let mut req = Vec::with_capacity(64);
+    req.extend_from_slice(&[0x04, 0x01]); // версия, CONNECT
+    req.extend_from_slice(&port.to_be_bytes());
+    let domain = match TargetAddr::new(host, port) {
+        TargetAddr::Ip(SocketAddr::V4(a)) => {
+            req.extend_from_slice(&a.ip().octets());
+            None
         }
-        0x03 => {
-            let mut l = [0u8; 1];
-            s.read_exact(&mut l)?;
-            let mut n = vec![0u8; l[0] as usize];
-            s.read_exact(&mut n)?;
+        TargetAddr::Ip(SocketAddr::V6(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SOCKS4 does not support IPv6 destinations",
+            ));
         }
-        0x04 => {
-            let mut skip = [0u8; 16];
-            s.read_exact(&mut skip)?;
+        TargetAddr::Domain(name, _) if socks4a => {
+            req.extend_from_slice(&[0, 0, 0, 1]);
+            Some(name)
         }
-        _ => return Err(io::Error::other("SOCKS5 bad ATYP in reply")),
+        TargetAddr::Domain(..) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SOCKS4 needs an IPv4 address; use socks4a for host names",
+            ));
+        }
+    };
+    let user_id = user_id.unwrap_or("");
+    if user_id.contains('\0') || domain.as_ref().is_some_and(|d| d.contains('\0')) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SOCKS4 fields must not contain NUL",
+        ));
+    }
+    req.extend_from_slice(user_id.as_bytes());
+    req.push(0);
+    if let Some(name) = domain {
+        req.extend_from_slice(name.as_bytes());
+        req.push(0);
+    }
+
+    let mut s = upstream_tcp(socks_addr)?;
+    s.write_all(&req)?;
+    // VN, CD, DSTPORT, DSTIP; 0x5a — запрос принят.
+    let mut resp = [0u8; 8];
+    s.read_exact(&mut resp)?;
+    if resp[0] != 0x00 {
+        return Err(io::Error::other("SOCKS4 bad reply version"));
+    }
+    if resp[1] != 0x5a {
+        let reply_code = resp[1];
+        return Err(io::Error::other(format!(
+            "SOCKS4 connect failed: 0x{reply_code:02x}"
+        )));
+    }
+    Ok(s)
+}
+
+// Туннель через родительский HTTP-прокси: CONNECT с необязательной
+// Basic-авторизацией. Любой ответ, кроме 2xx, считается ошибкой.
+pub fn http_connect(
+    proxy_addr: &str,
+    host: &str,
+    port: u16,
+    user: Option<&str>,
+    pass: Option<&str>,
+) -> io::Result<TcpStream> {
+    let authority = TargetAddr::new(host, port).to_string();
+    let mut req = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(user) = user {
+        let credentials = format!("{user}:{}", pass.unwrap_or(""));
+        req.push_str("Proxy-Authorization: Basic ");
+        req.push_str(&base64_encode(credentials.as_bytes()));
+        req.push_str("\r\n");
     }
-    let mut p = [0u8; 2];
-    s.read_exact(&mut p)?;
+    req.push_str("\r\n");
 
+    let mut s = upstream_tcp(proxy_addr)?;
+    s.write_all(req.as_bytes())?;
+
+    // Читаем по байту, чтобы не забрать из сокета данные туннеля,
+    // пришедшие сразу за ответом.
+    let mut head = Vec::with_capacity(256);
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if s.read(&mut byte)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "HTTP proxy closed the connection",
+            ));
+        }
+        head.push(byte[0]);
+        if head.len() > 64 * 1024 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Headers too large",
+            ));
+        }
+    }
+    let resp = parse_response_head(&head)?;
+    if !(200..300).contains(&resp.status) {
+        return Err(io::Error::other(format!(
+            "HTTP proxy CONNECT failed: {} {}",
+            resp.status, resp.reason
+        )));
+    }
     Ok(s)
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UpstreamProtocol {
+    Socks4,
+    Socks4a,
+    Socks5,
+    // SOCKS5 с разрешением имён на сервере; так работает адрес без схемы.
+    #[default]
+    Socks5h,
+    // SOCKS5 внутри TLS: учётные данные RFC 1929 не идут открытым текстом.
+    #[cfg(feature = "tls")]
+    Socks5Tls,
+    // Родительский HTTP-прокси с методом CONNECT.
+    Http,
+    // Без прокси: прямое подключение к назначению.
+    Direct,
+}
+
+impl UpstreamProtocol {
+    // Передаются ли серверу имена (socks4a, socks5h, socks5+tls, http) или
+    // только адреса; при прямом подключении имена разрешаются локально.
+    #[must_use]
+    pub const fn remote_dns(self) -> bool {
+        match self {
+            Self::Socks4a | Self::Socks5h | Self::Http => true,
+            #[cfg(feature = "tls")]
+            Self::Socks5Tls => true,
+            _ => false,
+        }
+    }
+
+    // Только SOCKS5 умеет рукопожатие внутри чужого туннеля (звено цепочки).
+    #[must_use]
+    pub const fn is_socks5(self) -> bool {
+        match self {
+            Self::Socks5 | Self::Socks5h => true,
+            #[cfg(feature = "tls")]
+            Self::Socks5Tls => true,
+            _ => false,
+        }
+    }
+}
+
+// Вышестоящий прокси: `[scheme://][user[:pass]@]host:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    pub protocol: UpstreamProtocol,
+    pub addr: String,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    // Настройки TLS для socks5+tls; без них — встроенные корневые
+    // сертификаты.
+    #[cfg(feature = "tls")]
+    pub tls: Option<UpstreamTlsConfig>,
+    // Имя для SNI и проверки сертификата вместо хоста из `addr`.
+    #[cfg(feature = "tls")]
+    pub tls_server_name: Option<String>,
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            protocol: UpstreamProtocol::Socks5h,
+            addr: "127.0.0.1:1080".to_owned(),
+            user: None,
+            pass: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            tls_server_name: None,
+        }
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_owned());
+        if s.eq_ignore_ascii_case("direct") {
+            return Ok(Self {
+                protocol: UpstreamProtocol::Direct,
+                addr: String::new(),
+                user: None,
+                pass: None,
+                #[cfg(feature = "tls")]
+                tls: None,
+                #[cfg(feature = "tls")]
+                tls_server_name: None,
+            });
+        }
+        let (protocol, rest) = match s.split_once("://") {
+            None => (UpstreamProtocol::Socks5h, s),
+            Some((scheme, rest)) => {
+                let protocol = match scheme.to_ascii_lowercase().as_str() {
+                    "socks4" => UpstreamProtocol::Socks4,
+                    "socks4a" => UpstreamProtocol::Socks4a,
+                    "socks5" => UpstreamProtocol::Socks5,
+                    "socks5h" => UpstreamProtocol::Socks5h,
+                    #[cfg(feature = "tls")]
+                    "socks5+tls" => UpstreamProtocol::Socks5Tls,
+                    #[cfg(not(feature = "tls"))]
+                    "socks5+tls" => {
+                        return Err(invalid("socks5+tls:// requires the tls feature"));
+                    }
+                    "http" => UpstreamProtocol::Http,
+                    _ => {
+                        return Err(invalid(
+                            "expected socks4://, socks4a://, socks5://, socks5h://, \
+                             socks5+tls:// or http://",
+                        ));
+                    }
+                };
+                (protocol, rest.trim_end_matches('/'))
+            }
+        };
+        let (user, pass, addr) = match rest.rsplit_once('@') {
+            Some((userinfo, addr)) => match userinfo.split_once(':') {
+                Some((u, p)) => (Some(u.to_owned()), Some(p.to_owned()), addr),
+                None => (Some(userinfo.to_owned()), None, addr),
+            },
+            None => (None, None, rest),
+        };
+        if addr
+            .rsplit_once(':')
+            .is_none_or(|(h, p)| h.is_empty() || p.parse::<u16>().is_err())
+        {
+            return Err(invalid("expected host:port"));
+        }
+        Ok(Self {
+            protocol,
+            addr: addr.to_owned(),
+            user,
+            pass,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            tls_server_name: None,
+        })
+    }
+}
+
+impl std::fmt::Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = match self.protocol {
+            UpstreamProtocol::Socks4 => "socks4",
+            UpstreamProtocol::Socks4a => "socks4a",
+            UpstreamProtocol::Socks5 => "socks5",
+            UpstreamProtocol::Socks5h => "socks5h",
+            #[cfg(feature = "tls")]
+            UpstreamProtocol::Socks5Tls => "socks5+tls",
+            UpstreamProtocol::Http => "http",
+            UpstreamProtocol::Direct => return f.write_str("direct"),
+        };
+        // Учётные данные в логи не попадают.
+        write!(f, "{scheme}://{}", self.addr)
+    }
+}
+
+impl Upstream {
+    // Адрес сервера как назначение для предыдущего звена цепочки.
+    fn host_port(&self) -> io::Result<(&str, u16)> {
+        self.addr
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "expected host:port"))
+    }
+
+    // Для SOCKS4 пароль не используется: имя пользователя уходит как USERID.
+    pub fn connect(&self, host: &str, port: u16) -> io::Result<UpstreamStream> {
+        let user = self.user.as_deref();
+        match self.protocol {
+            UpstreamProtocol::Socks4 | UpstreamProtocol::Socks4a => socks4_connect(
+                &self.addr,
+                host,
+                port,
+                user,
+                self.protocol == UpstreamProtocol::Socks4a,
+            )
+            .map(UpstreamStream::Tcp),
+            UpstreamProtocol::Http => {
+                http_connect(&self.addr, host, port, user, self.pass.as_deref())
+                    .map(UpstreamStream::Tcp)
+            }
+            UpstreamProtocol::Direct => {
+                let s = match TargetAddr::new(host, port) {
+                    TargetAddr::Ip(addr) => upstream_tcp(addr)?,
+                    TargetAddr::Domain(name, port) => upstream_tcp((name.as_str(), port))?,
+                };
+                Ok(UpstreamStream::Tcp(s))
+            }
+            _ => {
+                let s = upstream_tcp(self.addr.as_str())?;
+                self.connect_over(UpstreamStream::Tcp(s), host, port)
+            }
+        }
+    }
+
+    // SOCKS5-рукопожатие внутри уже открытого потока (звено цепочки);
+    // для socks5+tls сначала устанавливается TLS.
+    fn connect_over(
+        &self,
+        stream: UpstreamStream,
+        host: &str,
+        port: u16,
+    ) -> io::Result<UpstreamStream> {
+        let stream = match self.protocol {
+            #[cfg(feature = "tls")]
+            UpstreamProtocol::Socks5Tls => {
+                let config = match &self.tls {
+                    Some(config) => config.0.clone(),
+                    None => tls_client_config(None)?,
+                };
+                let name = match &self.tls_server_name {
+                    Some(name) => name.as_str(),
+                    None => self.host_port()?.0,
+                };
+                UpstreamStream::Tls(tls_connect(stream, config, name)?)
+            }
+            protocol if protocol.is_socks5() => stream,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{self} cannot be used inside a tunnel"),
+                ));
+            }
+        };
+        socks5_connect_over(
+            stream,
+            host,
+            port,
+            self.user.as_deref(),
+            self.pass.as_deref(),
+        )
+    }
+}
+
+// Соединение с вышестоящим сервером: сокет или TLS-сессия поверх
+// предыдущего звена.
+#[derive(Debug)]
+pub enum UpstreamStream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(TlsClientStream<Self>),
+}
+
+impl Read for UpstreamStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.read(buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for UpstreamStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.write(buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.flush(),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.flush(),
+        }
+    }
+}
+
+impl Duplex for UpstreamStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(s) => s.try_clone().map(Self::Tcp),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.try_clone().map(Self::Tls),
+        }
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => Duplex::shutdown_write(s),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.shutdown_write(),
+        }
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Tcp(s) => Some(s),
+            #[cfg(feature = "tls")]
+            Self::Tls(_) => None,
+        }
+    }
+}
+
+// Цепочка серверов: каждое звено подключается к следующему, последнее —
+// к назначению. Звенья после первого должны быть SOCKS5: их рукопожатие
+// идёт внутри туннеля предыдущего.
+pub fn connect_chain(chain: &[Upstream], host: &str, port: u16) -> io::Result<UpstreamStream> {
+    let Some((first, rest)) = chain.split_first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "empty upstream chain",
+        ));
+    };
+    if let Some(hop) = rest.iter().find(|hop| !hop.protocol.is_socks5()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("chain hop {hop} is not a SOCKS5 server"),
+        ));
+    }
+    let Some(next) = rest.first() else {
+        return first.connect(host, port);
+    };
+    let (next_host, next_port) = next.host_port()?;
+    let mut stream = first.connect(next_host, next_port)?;
+    for (i, hop) in rest.iter().enumerate() {
+        let (target_host, target_port) = match rest.get(i + 1) {
+            Some(after) => after.host_port()?,
+            None => (host, port),
+        };
+        stream = hop.connect_over(stream, target_host, target_port)?;
+    }
+    Ok(stream)
+}
+
+// BIND (RFC 1928): сервер открывает порт для входящего соединения.
+// Первый ответ сообщает адрес, на котором сервер слушает, второй —
+// адрес подключившегося узла; после него поток несёт данные этого узла.
+#[derive(Debug)]
+pub struct Socks5Bind {
+    stream: TcpStream,
+    bound: TargetAddr,
+}
+
+impl Socks5Bind {
+    // `host`/`port` — ожидаемый узел (обычно адрес из основной сессии,
+    // например FTP-сервер); сервер может отклонять чужие подключения.
+    pub fn open(
+        socks_addr: &str,
+        host: &str,
+        port: u16,
+        user: Option<&str>,
+        pass: Option<&str>,
+    ) -> io::Result<Self> {
+        let mut stream = socks5_open(socks_addr, user, pass)?;
+        let mut bound = socks5_request(&mut stream, 0x02, &TargetAddr::new(host, port))?;
+        // Нулевой адрес означает адрес самого SOCKS-сервера.
+        if let TargetAddr::Ip(addr) = &mut bound
+            && addr.ip().is_unspecified()
+        {
+            addr.set_ip(stream.peer_addr()?.ip());
+        }
+        Ok(Self { stream, bound })
+    }
+
+    // Адрес, который нужно сообщить удалённой стороне (например, в PORT).
+    #[must_use]
+    pub const fn bound_addr(&self) -> &TargetAddr {
+        &self.bound
+    }
+
+    // Ждёт второй ответ. `timeout` ограничивает ожидание подключения
+    // (`None` — без ограничения); затем восстанавливается обычный таймаут.
+    pub fn accept(mut self, timeout: Option<Duration>) -> io::Result<(TcpStream, TargetAddr)> {
+        self.stream.set_read_timeout(timeout)?;
+        let peer = read_socks5_reply(&mut self.stream, 0x02)?;
+        self.stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+        Ok((self.stream, peer))
+    }
+}
+
+// Заголовок UDP-датаграммы SOCKS5 (RFC 1928, раздел 7): RSV, FRAG, ATYP,
+// DST.ADDR, DST.PORT, затем данные.
+pub fn encode_udp_datagram(target: &TargetAddr, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(payload.len() + 22);
+    packet.extend_from_slice(&[0, 0, 0]); // RSV, FRAG
+    push_socks5_addr(&mut packet, target)?;
+    packet.extend_from_slice(payload);
+    Ok(packet)
+}
+
+// Разбирает датаграмму ретранслятора; фрагменты (FRAG != 0) не
+// поддерживаются и дают ошибку `Unsupported`.
+pub fn decode_udp_datagram(packet: &[u8]) -> io::Result<(TargetAddr, &[u8])> {
+    let mut r = packet;
+    let mut hdr = [0u8; 4];
+    r.read_exact(&mut hdr)?;
+    if hdr[2] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "fragmented SOCKS5 datagram",
+        ));
+    }
+    let from = read_socks5_addr(&mut r, hdr[3])?;
+    Ok((from, r))
+}
+
+// Связка UDP ASSOCIATE. Ретранслятор работает, пока открыто управляющее
+// TCP-соединение, поэтому оно хранится вместе с UDP-сокетом.
+#[derive(Debug)]
+pub struct Socks5Datagram {
+    control: TcpStream,
+    socket: UdpSocket,
+    relay: SocketAddr,
+}
+
+impl Socks5Datagram {
+    pub fn associate(socks_addr: &str, user: Option<&str>, pass: Option<&str>) -> io::Result<Self> {
+        let control = socks5_open(socks_addr, user, pass)?;
+        let server = control.peer_addr()?;
+        let socket = UdpSocket::bind(SocketAddr::new(any_ip(server.ip()), 0))?;
+        Self::finish(control, server, socket)
+    }
+
+    // Как `associate`, но UDP-сокет привязывается к `local`; его адрес
+    // сообщается серверу (некоторые серверы принимают датаграммы только
+    // с заявленного адреса).
+    pub fn bind(
+        socks_addr: &str,
+        local: SocketAddr,
+        user: Option<&str>,
+        pass: Option<&str>,
+    ) -> io::Result<Self> {
+        let control = socks5_open(socks_addr, user, pass)?;
+        let server = control.peer_addr()?;
+        let socket = UdpSocket::bind(local)?;
+        Self::finish(control, server, socket)
+    }
+
+    fn finish(mut control: TcpStream, server: SocketAddr, socket: UdpSocket) -> io::Result<Self> {
+        let local = TargetAddr::Ip(socket.local_addr()?);
+        let mut relay = match socks5_request(&mut control, 0x03, &local)? {
+            TargetAddr::Ip(addr) => addr,
+            TargetAddr::Domain(..) => {
+                return Err(io::Error::other("SOCKS5 relay address is not an IP"));
+            }
+        };
+        // Нулевой адрес ретранслятора означает адрес самого SOCKS-сервера.
+        if relay.ip().is_unspecified() {
+            relay.set_ip(server.ip());
+        }
+        socket.connect(relay)?;
+        Ok(Self {
+            control,
+            socket,
+            relay,
+        })
+    }
+
+    #[must_use]
+    pub const fn relay_addr(&self) -> SocketAddr {
+        self.relay
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_write_timeout(timeout)
+    }
+
+    // Возвращает размер отправленных данных без заголовка.
+    pub fn send_to(&self, payload: &[u8], target: &TargetAddr) -> io::Result<usize> {
+        self.socket.send(&encode_udp_datagram(target, payload)?)?;
+        Ok(payload.len())
+    }
+
+    // Фрагментированные датаграммы пропускаются. Данные, не поместившиеся
+    // в `buf`, отбрасываются, как у `UdpSocket::recv_from`, поэтому
+    // принимать больше `buf` с самым длинным заголовком незачем.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
+        let mut packet = vec![0u8; (buf.len() + UDP_HEADER_MAX).min(65_536)];
+        loop {
+            let n = self.socket.recv(&mut packet)?;
+            let (from, payload) = match decode_udp_datagram(&packet[..n]) {
+                Ok(d) => d,
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => continue,
+                Err(e) => return Err(e),
+            };
+            let len = payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&payload[..len]);
+            return Ok((len, from));
+        }
+    }
+
+    // Управляющее соединение: его закрытие завершает ассоциацию.
+    #[must_use]
+    pub const fn control(&self) -> &TcpStream {
+        &self.control
+    }
+}
+
+// RSV, FRAG, ATYP, имя домена с длиной и порт.
+const UDP_HEADER_MAX: usize = 2 + 1 + 1 + 256 + 2;
+
+const fn any_ip(like: IpAddr) -> IpAddr {
+    match like {
+        IpAddr::V4(_) => IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+    }
+}
@@ -1,26 +1,110 @@
 use std::env;
-use std::io::{self, Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::sync::Arc;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Clone, Debug)]
 struct Config {
     listen: String,
-    socks_addr: String,
+    // Слушатель принимает только клиентов SOCKS5.
+    socks2http: bool,
+    upstream: Upstream,
+    // Следующие звенья цепочки после `upstream`.
+    hops: Vec<Upstream>,
+    socks_tls_ca: Option<String>,
+    socks_tls_cert: Option<String>,
+    socks_tls_key: Option<String>,
+    socks_tls_sni: Option<String>,
+    socks_tls_insecure: bool,
     username: Option<String>,
     password: Option<String>,
     verbose: bool,
+    forwarding: ForwardingOptions,
+    header_rules: HeaderRules,
+    tls_origination: bool,
+    tls_upgrade: Vec<HostPattern>,
+    tls_ca: Option<String>,
+    listen_tls_cert: Option<String>,
+    listen_tls_key: Option<String>,
+    listen_tls_client_ca: Option<String>,
+    #[cfg(feature = "tls")]
+    tls_client: Option<Arc<rustls::ClientConfig>>,
+    #[cfg(feature = "tls")]
+    tls_server: Option<Arc<rustls::ServerConfig>>,
+    mitm_ca_cert: Option<String>,
+    mitm_ca_key: Option<String>,
+    mitm_hosts: Vec<HostPattern>,
+    mitm_dump: Option<String>,
+    #[cfg(feature = "mitm")]
+    mitm: Option<Arc<MitmCa>>,
+    capture: Option<Arc<CaptureLog>>,
+    capture_body_limit: usize,
+    acl: Acl,
+    clients: ClientFilter,
+    ssrf: Option<SsrfGuard>,
+    dns: DnsOptions,
+    dns_cache_size: Option<usize>,
+    dns_server: Option<SocketAddr>,
+    dns_over_socks: bool,
+    dns_ttl: u64,
+    dns_negative_ttl: u64,
+    dns_hosts: Vec<(String, IpAddr)>,
 }
 
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+// Сколько ждать ClientHello после ответа на CONNECT.
+const SNI_PEEK_TIMEOUT: Duration = Duration::from_secs(5);
+
 fn parse_args() -> Config {
     let mut cfg = Config {
         listen: "127.0.0.1:8080".to_string(),
-        socks_addr: "127.0.0.1:1080".to_string(),
+        socks2http: false,
+        upstream: Upstream::default(),
+        hops: Vec::new(),
+        socks_tls_ca: None,
+        socks_tls_cert: None,
+        socks_tls_key: None,
+        socks_tls_sni: None,
+        socks_tls_insecure: false,
         username: None,
         password: None,
         verbose: false,
+        forwarding: ForwardingOptions::default(),
+        header_rules: HeaderRules::default(),
+        tls_origination: false,
+        tls_upgrade: Vec::new(),
+        tls_ca: None,
+        listen_tls_cert: None,
+        listen_tls_key: None,
+        listen_tls_client_ca: None,
+        #[cfg(feature = "tls")]
+        tls_client: None,
+        #[cfg(feature = "tls")]
+        tls_server: None,
+        mitm_ca_cert: None,
+        mitm_ca_key: None,
+        mitm_hosts: Vec::new(),
+        mitm_dump: None,
+        #[cfg(feature = "mitm")]
+        mitm: None,
+        capture: None,
+        capture_body_limit: 64 * 1024,
+        acl: Acl::default(),
+        clients: ClientFilter::default(),
+        ssrf: None,
+        dns: DnsOptions::default(),
+        dns_cache_size: None,
+        dns_server: None,
+        dns_over_socks: false,
+        dns_ttl: 60,
+        dns_negative_ttl: 30,
+        dns_hosts: Vec::new(),
     };
 
     let mut it = env::args().skip(1);
@@ -31,11 +115,14 @@ fn parse_args() -> Config {
                     cfg.listen = v;
                 }
             }
-            "--socks" | "-s" => {
-                if let Some(v) = it.next() {
-                    cfg.socks_addr = v;
-                }
-            }
+            "--socks2http" => cfg.socks2http = true,
+            "--socks" | "-s" | "--upstream" => cfg.upstream = parse_value(&arg, it.next()),
+            "--socks-hop" => cfg.hops.push(parse_value(&arg, it.next())),
+            "--socks-tls-ca" => cfg.socks_tls_ca = Some(parse_value(&arg, it.next())),
+            "--socks-tls-cert" => cfg.socks_tls_cert = Some(parse_value(&arg, it.next())),
+            "--socks-tls-key" => cfg.socks_tls_key = Some(parse_value(&arg, it.next())),
+            "--socks-tls-sni" => cfg.socks_tls_sni = Some(parse_value(&arg, it.next())),
+            "--socks-tls-insecure" => cfg.socks_tls_insecure = true,
             "--user" | "-u" => {
                 if let Some(v) = it.next() {
                     cfg.username = Some(v);
@@ -46,15 +133,200 @@ fn parse_args() -> Config {
                     cfg.password = Some(v);
                 }
             }
+            "--via" => cfg.forwarding.via = parse_value(&arg, it.next()),
+            "--forwarded" => cfg.forwarding.client_ip = parse_value(&arg, it.next()),
+            "--forwarded-header" => {
+                cfg.forwarding.client_ip_header = parse_value(&arg, it.next());
+            }
+            "--privacy" => cfg.forwarding = ForwardingOptions::privacy(),
+            "--header-rule" => cfg.header_rules.push(parse_value(&arg, it.next())),
+            "--header-rules" => {
+                let path: String = parse_value(&arg, it.next());
+                let rules = fs::read_to_string(&path)
+                    .and_then(|text| HeaderRules::parse(&text))
+                    .unwrap_or_else(|e| {
+                        eprintln!("Cannot load header rules from {path}: {e}");
+                        std::process::exit(2);
+                    });
+                cfg.header_rules.append(rules);
+            }
+            "--tls-origination" => cfg.tls_origination = true,
+            "--tls-upgrade" => cfg.tls_upgrade.push(parse_value(&arg, it.next())),
+            "--tls-ca" => cfg.tls_ca = Some(parse_value(&arg, it.next())),
+            "--listen-tls-cert" => cfg.listen_tls_cert = Some(parse_value(&arg, it.next())),
+            "--listen-tls-key" => cfg.listen_tls_key = Some(parse_value(&arg, it.next())),
+            "--listen-tls-client-ca" => {
+                cfg.listen_tls_client_ca = Some(parse_value(&arg, it.next()));
+            }
+            "--mitm-ca-cert" => cfg.mitm_ca_cert = Some(parse_value(&arg, it.next())),
+            "--mitm-ca-key" => cfg.mitm_ca_key = Some(parse_value(&arg, it.next())),
+            "--mitm-host" => cfg.mitm_hosts.push(parse_value(&arg, it.next())),
+            "--mitm-dump" => cfg.mitm_dump = Some(parse_value(&arg, it.next())),
+            "--capture" => {
+                let path: String = parse_value(&arg, it.next());
+                match CaptureLog::open(Path::new(&path)) {
+                    Ok(log) => cfg.capture = Some(Arc::new(log)),
+                    Err(e) => {
+                        eprintln!("Cannot open capture file {path}: {e}");
+                        std::process::exit(2);
+                    }
+                }
+            }
+            "--capture-body-limit" => cfg.capture_body_limit = parse_value(&arg, it.next()),
+            "--acl" => cfg.acl.push(parse_value(&arg, it.next())),
+            "--acl-file" => {
+                let path: String = parse_value(&arg, it.next());
+                if let Err(e) =
+                    fs::read_to_string(&path).and_then(|text| cfg.acl.parse_rules(&text))
+                {
+                    eprintln!("Cannot load ACL rules from {path}: {e}");
+                    std::process::exit(2);
+                }
+            }
+            "--connect-port" => cfg.acl.allow_connect_port(parse_value(&arg, it.next())),
+            "--client-allow" => cfg.clients.allow.push(parse_value(&arg, it.next())),
+            "--client-deny" => cfg.clients.deny.push(parse_value(&arg, it.next())),
+            "--block-private" => {
+                cfg.ssrf.get_or_insert_with(SsrfGuard::default);
+            }
+            "--private-allow" => {
+                let range = parse_value(&arg, it.next());
+                cfg.ssrf
+                    .get_or_insert_with(SsrfGuard::default)
+                    .allow
+                    .push(range);
+            }
+            "--dns" => cfg.dns.mode = parse_value(&arg, it.next()),
+            "--dns-family" => cfg.dns.family = parse_value(&arg, it.next()),
+            "--happy-eyeballs-delay" => {
+                let ms: u64 = parse_value(&arg, it.next());
+                cfg.dns.happy_eyeballs = Some(Duration::from_millis(ms));
+            }
+            "--no-happy-eyeballs" => cfg.dns.happy_eyeballs = None,
+            "--dns-cache" => cfg.dns_cache_size = Some(parse_value(&arg, it.next())),
+            "--dns-server" => cfg.dns_server = Some(parse_value(&arg, it.next())),
+            "--dns-over-socks" => cfg.dns_over_socks = true,
+            "--dns-ttl" => cfg.dns_ttl = parse_value(&arg, it.next()),
+            "--dns-negative-ttl" => cfg.dns_negative_ttl = parse_value(&arg, it.next()),
+            "--dns-host" => {
+                let entry: String = parse_value(&arg, it.next());
+                let Some((name, addrs)) = entry.split_once('=') else {
+                    eprintln!("Invalid value for {arg}: {entry} (expected name=ip[,ip])");
+                    std::process::exit(2);
+                };
+                for addr in addrs.split(',') {
+                    let ip = parse_value(&arg, Some(addr.trim().to_owned()));
+                    cfg.dns_hosts.push((name.trim().to_owned(), ip));
+                }
+            }
+            "--dns-hosts" => {
+                let path: String = parse_value(&arg, it.next());
+                match fs::read_to_string(&path).and_then(|text| parse_hosts(&text)) {
+                    Ok(entries) => cfg.dns_hosts.extend(entries),
+                    Err(e) => {
+                        eprintln!("Cannot load hosts from {path}: {e}");
+                        std::process::exit(2);
+                    }
+                }
+            }
             "--verbose" | "-v" => cfg.verbose = true,
             "--help" | "-h" => {
                 eprintln!(
                     "http2socks-proxy
 Usage: http2socks-proxy [options]
   -l, --listen <addr>   Listen address (default 127.0.0.1:8080)
-  -s, --socks <addr>    SOCKS5 server address (default 127.0.0.1:1080)
-  -u, --user <user>     SOCKS5 username (optional)
-  -p, --pass <pass>     SOCKS5 password (optional)
+      --socks2http      Accept only SOCKS5 clients (otherwise they are told
+                        apart from HTTP ones by the first byte)
+  -s, --socks, --upstream <url>
+                        Upstream proxy: [socks4|socks4a|socks5|socks5h|http://]
+                        [user[:pass]@]host:port (default 127.0.0.1:1080),
+                        or \"direct\" to connect without a proxy
+      --socks-hop <url> Next SOCKS5 server in a chain after --socks (repeatable)
+      --socks-tls-ca <file>
+                        CA bundle (PEM) for socks5+tls:// servers
+                        (default: built-in Mozilla roots)
+      --socks-tls-cert <file>, --socks-tls-key <file>
+                        Client certificate and key (PEM) for socks5+tls://
+      --socks-tls-sni <name>
+                        Server name for SNI and certificate checks of the
+                        --socks server (default: its host)
+      --socks-tls-insecure
+                        Do not verify socks5+tls:// server certificates
+  -u, --user <user>     Upstream username (SOCKS4 user ID)
+  -p, --pass <pass>     SOCKS5 or HTTP proxy password (optional)
+      --via <mode>      Via header: add, strip or leave (default leave)
+      --forwarded <mode>
+                        Client IP header: add, strip or leave (default leave)
+      --forwarded-header <name>
+                        forwarded or x-forwarded-for (default forwarded)
+      --privacy         Strip Via and all client IP headers
+      --header-rule <rule>
+                        Header rewrite rule (repeatable), e.g.
+                        \"request *.example.com set User-Agent: x\"
+      --header-rules <file>
+                        Load header rewrite rules, one per line
+      --tls-origination Fetch https:// absolute-form targets over TLS
+                        (requires the tls feature)
+      --tls-upgrade <host-pattern>
+                        Send http:// requests to these hosts over TLS
+      --tls-ca <file>   CA bundle (PEM) for origin certificates
+                        (default: built-in Mozilla roots)
+      --listen-tls-cert <file>
+                        Serve the proxy over TLS with this PEM certificate
+      --listen-tls-key <file>
+                        PEM private key for --listen-tls-cert
+      --listen-tls-client-ca <file>
+                        Require client certificates signed by this CA
+      --mitm-host <host-pattern>
+                        Decrypt CONNECT tunnels to these hosts (repeatable,
+                        requires the mitm feature)
+      --mitm-ca-cert <file>
+                        Local CA certificate (PEM) for minted certificates
+      --mitm-ca-key <file>
+                        Private key (PEM) of the local CA
+      --mitm-dump <dir> Directory for decrypted request/response dumps
+      --capture <file>  Append request/response records (JSON Lines)
+      --capture-body-limit <bytes>
+                        Max captured body size per message (default 65536)
+      --acl <rule>      Destination access rule (repeatable), e.g.
+                        \"deny domain:example.com\" or \"allow ip:10.0.0.0/8\"
+                        (ip: matches targets given as IP literals only)
+      --acl-file <file> Load access rules, one per line
+      --connect-port <port>
+                        Also allow CONNECT to this port (443 is always allowed)
+      --client-allow <cidr>
+                        Accept clients only from these ranges (repeatable)
+      --client-deny <cidr>
+                        Refuse clients from these ranges (repeatable)
+      --block-private   Refuse private, loopback, link-local and metadata
+                        destinations (names are resolved locally)
+      --private-allow <cidr>
+                        Exempt this range from --block-private (repeatable)
+      --dns <mode>      Where to resolve destination names: remote (SOCKS
+                        server), local or local-first (local, then the name
+                        via SOCKS if it fails to resolve or connect)
+                        (default remote)
+      --dns-family <pref>
+                        any, prefer-ipv4, prefer-ipv6, ipv4 or ipv6
+      --happy-eyeballs-delay <ms>
+                        Delay between parallel connection attempts (default 250)
+      --no-happy-eyeballs
+                        Try resolved addresses strictly one after another
+      --dns-cache <entries>
+                        Cache local lookups, up to this many names
+                        (implies --dns local unless set)
+      --dns-server <addr>
+                        Query this DNS server (ip:port) directly; answers are
+                        cached for their TTL
+      --dns-over-socks  Send --dns-server queries through the SOCKS server
+                        (UDP ASSOCIATE)
+      --dns-ttl <secs>  Cache time for system resolver answers (default 60)
+      --dns-negative-ttl <secs>
+                        Cache time for failed lookups (default 30)
+      --dns-host <name=ip[,ip]>
+                        Static address for a name (repeatable)
+      --dns-hosts <file>
+                        Static addresses in /etc/hosts format
   -v, --verbose         Verbose logs
   -h, --help            Show help
 "
@@ -68,18 +340,232 @@ Usage: http2socks-proxy [options]
         }
     }
 
-    // Если указан только пользователь или только пароль — требуем оба.
-    match (&cfg.username, &cfg.password) {
-        (Some(_), None) | (None, Some(_)) => {
+    // Учётные данные из URL важнее --user/--pass.
+    if cfg.upstream.user.is_none() {
+        cfg.upstream.user = cfg.username.take();
+        cfg.upstream.pass = cfg.password.take();
+    }
+    let socks4 = matches!(
+        cfg.upstream.protocol,
+        UpstreamProtocol::Socks4 | UpstreamProtocol::Socks4a
+    );
+    // Если указан только пользователь или только пароль — требуем оба
+    // (для SOCKS4 пароля нет).
+    match (&cfg.upstream.user, &cfg.upstream.pass) {
+        (Some(_), None) if !socks4 => {
+            eprintln!("Both --user and --pass are required when using auth");
+            std::process::exit(2);
+        }
+        (None, Some(_)) => {
             eprintln!("Both --user and --pass are required when using auth");
             std::process::exit(2);
         }
         _ => {}
     }
+    if cfg.hops.iter().any(|hop| !hop.protocol.is_socks5()) {
+        eprintln!("--socks-hop needs a socks5://, socks5h:// or socks5+tls:// server");
+        std::process::exit(2);
+    }
+    let socks_tls = cfg.socks_tls_ca.is_some()
+        || cfg.socks_tls_cert.is_some()
+        || cfg.socks_tls_key.is_some()
+        || cfg.socks_tls_sni.is_some()
+        || cfg.socks_tls_insecure;
+    if socks_tls {
+        load_upstream_tls(&mut cfg);
+    }
+    // Назначение получает последнее звено цепочки; socks4://, socks5:// и
+    // direct получают адреса, а не имена: разрешаем локально.
+    let last = cfg.hops.last().unwrap_or(&cfg.upstream).protocol;
+    if !last.remote_dns() && cfg.dns.mode == DnsMode::Remote {
+        cfg.dns.mode = DnsMode::Local;
+    }
+    if last == UpstreamProtocol::Socks4 {
+        cfg.dns.family = FamilyPreference::Ipv4Only;
+    }
+
+    if !cfg.mitm_hosts.is_empty() {
+        load_mitm(&mut cfg);
+    }
+    if cfg.tls_origination || !cfg.tls_upgrade.is_empty() || !cfg.mitm_hosts.is_empty() {
+        load_tls_client(&mut cfg);
+    }
+    match (&cfg.listen_tls_cert, &cfg.listen_tls_key) {
+        (Some(_), Some(_)) => load_tls_server(&mut cfg),
+        (None, None) if cfg.listen_tls_client_ca.is_none() => {}
+        _ => {
+            eprintln!("A TLS listener needs both --listen-tls-cert and --listen-tls-key");
+            std::process::exit(2);
+        }
+    }
+
+    if cfg.dns_over_socks && cfg.dns_server.is_none() {
+        eprintln!("--dns-over-socks needs --dns-server");
+        std::process::exit(2);
+    }
+    // UDP-ретранслятор работает только с обычным SOCKS5 без цепочки.
+    if cfg.dns_over_socks
+        && (!matches!(
+            cfg.upstream.protocol,
+            UpstreamProtocol::Socks5 | UpstreamProtocol::Socks5h
+        ) || !cfg.hops.is_empty())
+    {
+        eprintln!("--dns-over-socks needs a single SOCKS5 server");
+        std::process::exit(2);
+    }
+    if cfg.dns_cache_size.is_some() || cfg.dns_server.is_some() || !cfg.dns_hosts.is_empty() {
+        load_dns_cache(&mut cfg);
+    }
 
     cfg
 }
 
+fn load_dns_cache(cfg: &mut Config) {
+    let source = match cfg.dns_server {
+        Some(server) if cfg.dns_over_socks => DnsSource::Socks {
+            socks_addr: cfg.upstream.addr.clone(),
+            user: cfg.upstream.user.clone(),
+            pass: cfg.upstream.pass.clone(),
+            server,
+        },
+        Some(server) => DnsSource::Server(server),
+        None => DnsSource::System {
+            ttl: Duration::from_secs(cfg.dns_ttl),
+        },
+    };
+    let mut cache = DnsCache::new(
+        source,
+        cfg.dns_cache_size.unwrap_or(1024),
+        Duration::from_secs(cfg.dns_negative_ttl),
+    );
+    for (name, ip) in &cfg.dns_hosts {
+        cache.add_host(name, *ip);
+    }
+    cache.set_family(cfg.dns.family);
+    cfg.dns.cache = Some(Arc::new(cache));
+    // Кеш имеет смысл только при локальном разрешении имён.
+    if cfg.dns.mode == DnsMode::Remote {
+        cfg.dns.mode = DnsMode::Local;
+    }
+}
+
+#[cfg(feature = "tls")]
+fn load_tls_client(cfg: &mut Config) {
+    match tls_client_config(cfg.tls_ca.as_deref().map(Path::new)) {
+        Ok(config) => cfg.tls_client = Some(config),
+        Err(e) => {
+            eprintln!("Cannot set up TLS origination: {e}");
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn load_tls_client(_cfg: &mut Config) {
+    eprintln!("TLS origination requires building with --features tls");
+    std::process::exit(2);
+}
+
+#[cfg(feature = "tls")]
+fn load_upstream_tls(cfg: &mut Config) {
+    let client_auth = match (&cfg.socks_tls_cert, &cfg.socks_tls_key) {
+        (Some(cert), Some(key)) => Some((Path::new(cert), Path::new(key))),
+        (None, None) => None,
+        _ => {
+            eprintln!("--socks-tls-cert and --socks-tls-key go together");
+            std::process::exit(2);
+        }
+    };
+    let config = match tls_upstream_config(
+        cfg.socks_tls_ca.as_deref().map(Path::new),
+        client_auth,
+        cfg.socks_tls_insecure,
+    ) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Cannot set up TLS to the SOCKS server: {e}");
+            std::process::exit(2);
+        }
+    };
+    let mut any = false;
+    for upstream in std::iter::once(&mut cfg.upstream).chain(&mut cfg.hops) {
+        if upstream.protocol == UpstreamProtocol::Socks5Tls {
+            upstream.tls = Some(UpstreamTlsConfig(config.clone()));
+            any = true;
+        }
+    }
+    if !any {
+        eprintln!("--socks-tls-* options need a socks5+tls:// server");
+        std::process::exit(2);
+    }
+    // Имя для SNI относится к серверу из --socks.
+    cfg.upstream.tls_server_name.clone_from(&cfg.socks_tls_sni);
+}
+
+#[cfg(not(feature = "tls"))]
+fn load_upstream_tls(_cfg: &mut Config) {
+    eprintln!("--socks-tls-* options require building with --features tls");
+    std::process::exit(2);
+}
+
+#[cfg(feature = "mitm")]
+fn load_mitm(cfg: &mut Config) {
+    let (Some(cert), Some(key), Some(_)) = (&cfg.mitm_ca_cert, &cfg.mitm_ca_key, &cfg.mitm_dump)
+    else {
+        eprintln!("--mitm-host needs --mitm-ca-cert, --mitm-ca-key and --mitm-dump");
+        std::process::exit(2);
+    };
+    match MitmCa::load(Path::new(cert), Path::new(key)) {
+        Ok(ca) => cfg.mitm = Some(Arc::new(ca)),
+        Err(e) => {
+            eprintln!("Cannot load MITM CA: {e}");
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(not(feature = "mitm"))]
+fn load_mitm(_cfg: &mut Config) {
+    eprintln!("MITM inspection requires building with --features mitm");
+    std::process::exit(2);
+}
+
+#[cfg(feature = "tls")]
+fn load_tls_server(cfg: &mut Config) {
+    let (Some(cert), Some(key)) = (&cfg.listen_tls_cert, &cfg.listen_tls_key) else {
+        return;
+    };
+    let client_ca = cfg.listen_tls_client_ca.as_deref().map(Path::new);
+    match tls_server_config(Path::new(cert), Path::new(key), client_ca) {
+        Ok(config) => {
+            cfg.tls_server = Some(config);
+            // Клиенты приходят по HTTPS, что и сообщается в Forwarded.
+            cfg.forwarding.listener = Scheme::Https;
+        }
+        Err(e) => {
+            eprintln!("Cannot set up TLS listener: {e}");
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn load_tls_server(_cfg: &mut Config) {
+    eprintln!("A TLS listener requires building with --features tls");
+    std::process::exit(2);
+}
+
+fn parse_value<T: FromStr<Err: Display>>(arg: &str, value: Option<String>) -> T {
+    let Some(v) = value else {
+        eprintln!("Missing value for {arg}");
+        std::process::exit(2);
+    };
+    v.parse().unwrap_or_else(|e| {
+        eprintln!("Invalid value for {arg}: {v} ({e})");
+        std::process::exit(2);
+    })
+}
+
 fn logv(cfg: &Config, msg: &str) {
     if cfg.verbose {
         eprintln!("{msg}");
@@ -89,8 +575,10 @@ fn logv(cfg: &Config, msg: &str) {
 fn main() -> io::Result<()> {
     let cfg = parse_args();
     eprintln!(
-        "Listening on {} and proxying via SOCKS5 {}",
-        cfg.listen, cfg.socks_addr
+        "Listening on {}{} and proxying via {}",
+        cfg.listen,
+        if cfg.socks2http { " (SOCKS5)" } else { "" },
+        cfg.upstream
     );
 
     let listener = TcpListener::bind(&cfg.listen)?;
@@ -98,10 +586,19 @@ fn main() -> io::Result<()> {
     let cfg = Arc::new(cfg);
     for conn in listener.incoming() {
         match conn {
-            Ok(mut client) => {
+            Ok(client) => {
+                // Клиенты вне разрешённых диапазонов отключаются сразу.
+                match client.peer_addr() {
+                    Ok(peer) if cfg.clients.permits(peer.ip()) => {}
+                    Ok(peer) => {
+                        logv(&cfg, &format!("client {peer} refused"));
+                        continue;
+                    }
+                    Err(_) => continue,
+                }
                 let cfg = cfg.clone();
                 thread::spawn(move || {
-                    if let Err(e) = handle_client(&mut client, &cfg)
+                    if let Err(e) = serve_connection(client, &cfg)
                         && cfg.verbose
                     {
                         eprintln!("client error: {e}");
@@ -117,90 +614,182 @@ fn main() -> io::Result<()> {
     Ok(())
 }
 
-fn read_until_double_crlf(stream: &mut TcpStream, buf: &mut Vec<u8>) -> io::Result<usize> {
-    let mut tmp = [0u8; 1024];
-    loop {
-        let n = stream.read(&mut tmp)?;
-        if n == 0 {
-            return Ok(0);
-        }
-        buf.extend_from_slice(&tmp[..n]);
-        if buf.windows(4).any(|w| w == b"\r\n\r\n") {
-            return Ok(buf.len());
-        }
-        // Ограничиваем размер заголовков, чтобы не переполнить буфер.
-        if buf.len() > 64 * 1024 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Headers too large",
-            ));
-        }
-    }
-}
-
 use http2socks_proxy::{
-    RequestTarget, parse_request_head, socks5_connect, write_modified_request_head,
+    Acl, BodyBuffer, CaptureLog, CaptureRecord, ClientFilter, ClientHello, Direction, DnsCache,
+    DnsMode, DnsOptions, DnsSource, Duplex, FamilyPreference, ForwardingOptions, HeaderRules,
+    HostPattern, HttpRecord, RequestTarget, Scheme, SsrfGuard, Tap, TargetAddr, TunnelRecord,
+    Upstream, UpstreamProtocol, UpstreamStream, apply_forwarding_headers, authority_host,
+    body_after_head, connect_chain, is_upgrade_request, parse_hosts, parse_request_head,
+    peek_client_hello, pipe_bidirectional, read_final_response_head, read_until_double_crlf,
+    socks5_accept, socks5_reply_code, socks5_write_reply, strip_hop_by_hop, write_error_response,
+    write_modified_request_head, write_modified_response_head,
+};
+#[cfg(feature = "mitm")]
+use http2socks_proxy::{MitmCa, MitmDump};
+#[cfg(feature = "tls")]
+use http2socks_proxy::{
+    UpstreamTlsConfig, tls_accept, tls_client_config, tls_connect, tls_server_config,
+    tls_upstream_config,
 };
 
-fn pipe_bidirectional(mut a: TcpStream, mut b: TcpStream) -> io::Result<()> {
-    let mut ar = a.try_clone()?;
-    let mut br = b.try_clone()?;
-    let t1 = thread::spawn(move || {
-        let _ = io::copy(&mut ar, &mut b);
-        let _ = b.shutdown(Shutdown::Write);
-    });
-    let t2 = thread::spawn(move || {
-        let _ = io::copy(&mut br, &mut a);
-        let _ = a.shutdown(Shutdown::Write);
-    });
-    let _ = t1.join();
-    let _ = t2.join();
-    Ok(())
+fn serve_connection(mut client: TcpStream, cfg: &Config) -> io::Result<()> {
+    client.set_read_timeout(Some(IO_TIMEOUT))?;
+    client.set_write_timeout(Some(IO_TIMEOUT))?;
+    let peer = client.peer_addr().ok();
+
+    // Для TLS-слушателя дальнейшая обработка идёт поверх расшифрованного потока.
+    #[cfg(feature = "tls")]
+    if let Some(config) = cfg.tls_server.clone() {
+        let mut client = tls_accept(client, config)?;
+        if cfg.socks2http {
+            return handle_socks(&mut client, peer, cfg);
+        }
+        return handle_client(&mut client, peer, cfg);
+    }
+
+    // Запрос HTTP не может начинаться с 0x05, поэтому по первому байту
+    // на одном порту обслуживаются и SOCKS5-, и HTTP-клиенты.
+    let mut first = [0u8; 1];
+    if cfg.socks2http || (client.peek(&mut first)? == 1 && first[0] == 0x05) {
+        return handle_socks(&mut client, peer, cfg);
+    }
+    handle_client(&mut client, peer, cfg)
 }
 
-fn handle_client(client: &mut TcpStream, cfg: &Config) -> io::Result<()> {
-    client.set_read_timeout(Some(Duration::from_secs(30)))?;
-    client.set_write_timeout(Some(Duration::from_secs(30)))?;
+// Клиент SOCKS5: поддерживается только CONNECT без аутентификации, дальше
+// туннель идёт так же, как для HTTP CONNECT, с тем же списком
+// --connect-port: иначе слушатель стал бы открытым ретранслятором на любой
+// порт.
+fn handle_socks<C: Duplex>(
+    client: &mut C,
+    peer: Option<SocketAddr>,
+    cfg: &Config,
+) -> io::Result<()> {
+    let req = socks5_accept(client, None)?;
+    let unspecified = TargetAddr::Ip(SocketAddr::from(([0, 0, 0, 0], 0)));
+    if req.command != 0x01 {
+        logv(
+            cfg,
+            &format!(
+                "SOCKS5 command 0x{:02x} to {} refused",
+                req.command, req.target
+            ),
+        );
+        return socks5_write_reply(client, 0x07, &unspecified);
+    }
+    let (host, port) = match req.target {
+        TargetAddr::Ip(addr) => (addr.ip().to_string(), addr.port()),
+        TargetAddr::Domain(host, port) => (host, port),
+    };
+    logv(cfg, &format!("SOCKS5 CONNECT {host}:{port}"));
+    let allowed = cfg.acl.check_connect(&host, port);
+    let checked = match allowed.and_then(|()| socks_target(&host, cfg)) {
+        Ok(checked) => checked,
+        Err(reason) => {
+            logv(
+                cfg,
+                &format!("SOCKS5 CONNECT {host}:{port} blocked: {reason}"),
+            );
+            return socks5_write_reply(client, 0x02, &unspecified);
+        }
+    };
+    let started = SystemTime::now();
+    let t0 = Instant::now();
+    let upstream = match connect_upstream(&host, checked, port, cfg) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            let _ = socks5_write_reply(client, socks5_reply_code(&e), &unspecified);
+            return Err(e);
+        }
+    };
+    socks5_write_reply(client, 0x00, &unspecified)?;
+    tunnel(client, upstream, host, port, peer, (started, t0), cfg)
+}
 
+fn handle_client<C: Duplex>(
+    client: &mut C,
+    peer: Option<SocketAddr>,
+    cfg: &Config,
+) -> io::Result<()> {
     let mut head = Vec::with_capacity(4096);
     if read_until_double_crlf(client, &mut head)? == 0 {
         return Ok(());
     }
 
-    let req = parse_request_head(&head)?;
+    let req = match parse_request_head(&head) {
+        Ok(req) => req,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            logv(cfg, &format!("bad request: {e}"));
+            return write_error_response(client, 400, "Bad Request", &e.to_string());
+        }
+        Err(e) => return Err(e),
+    };
     match req {
         RequestTarget::Connect { host, port } => {
             logv(cfg, &format!("CONNECT {host}:{port}"));
-            let upstream = socks5_connect(
-                &cfg.socks_addr,
-                &host,
-                port,
-                cfg.username.as_deref(),
-                cfg.password.as_deref(),
-            )?;
+            if let Err(reason) = cfg.acl.check_connect(&host, port) {
+                logv(cfg, &format!("CONNECT {host}:{port} blocked: {reason}"));
+                return write_error_response(client, 403, "Forbidden", &reason);
+            }
+            let checked = match socks_target(&host, cfg) {
+                Ok(checked) => checked,
+                Err(reason) => {
+                    logv(cfg, &format!("CONNECT {host}:{port} blocked: {reason}"));
+                    return write_error_response(client, 403, "Forbidden", &reason);
+                }
+            };
+            let started = SystemTime::now();
+            let t0 = Instant::now();
+            let upstream = connect_upstream(&host, checked, port, cfg)?;
             // Отвечаем клиенту 200 и начинаем туннелирование трафика.
             client.write_all(
                 b"HTTP/1.1 200 Connection Established\r\nProxy-Agent: http2socks-proxy\r\n\r\n",
             )?;
-            let a = client.try_clone()?;
-            let _ = pipe_bidirectional(a, upstream);
-            Ok(())
+            tunnel(client, upstream, host, port, peer, (started, t0), cfg)
         }
         RequestTarget::Http {
             method,
+            scheme,
             host,
             port,
             path,
             headers,
         } => {
-            logv(cfg, &format!("{method} http://{host}:{port}{path}"));
-            let mut upstream = socks5_connect(
-                &cfg.socks_addr,
-                &host,
-                port,
-                cfg.username.as_deref(),
-                cfg.password.as_deref(),
-            )?;
+            logv(
+                cfg,
+                &format!("{method} {}://{host}:{port}{path}", scheme.as_str()),
+            );
+            // Проверяем и цель запроса, и заголовок Host, если он отличается.
+            let host_header = headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("Host"))
+                .map(|(_, v)| authority_host(v));
+            if let Err(reason) = cfg
+                .acl
+                .check(&host, port)
+                .and_then(|()| host_header.map_or(Ok(()), |h| cfg.acl.check(h, port)))
+            {
+                logv(cfg, &format!("{method} {host}:{port} blocked: {reason}"));
+                return write_error_response(client, 403, "Forbidden", &reason);
+            }
+            if scheme == Scheme::Https && !cfg.tls_origination {
+                // Без --tls-origination клиент должен использовать CONNECT.
+                return write_error_response(
+                    client,
+                    400,
+                    "Bad Request",
+                    "https:// targets must be requested via CONNECT",
+                );
+            }
+            // Хосты из списка --tls-upgrade получают TLS вместо plain HTTP.
+            let upgraded =
+                scheme == Scheme::Http && cfg.tls_upgrade.iter().any(|p| p.matches(&host));
+            let use_tls = scheme == Scheme::Https || upgraded;
+            let connect_port = if upgraded && port == Scheme::Http.default_port() {
+                Scheme::Https.default_port()
+            } else {
+                port
+            };
 
             // Гарантируем наличие заголовка Host.
             let has_host = headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("Host"));
@@ -208,28 +797,355 @@ fn handle_client(client: &mut TcpStream, cfg: &Config) -> io::Result<()> {
             if !has_host {
                 headers_out.push((
                     "Host".to_owned(),
-                    if port == 80 {
-                        host
+                    if port == scheme.default_port() {
+                        host.clone()
                     } else {
                         format!("{host}:{port}")
                     },
                 ));
             }
 
-            write_modified_request_head(&mut upstream, &method, &path, &headers_out)?;
+            let client_ip = peer.map(|a| a.ip());
+            apply_forwarding_headers(&mut headers_out, &cfg.forwarding, client_ip);
+            cfg.header_rules
+                .apply(Direction::Request, &host, &mut headers_out);
 
-            // После заголовков пересылаем оставшиеся данные в обе стороны (тело запроса и ответ).
-            // Но сперва отправим наверх байты, уже прочитанные после CRLFCRLF.
-            if let Some(pos) = head.windows(4).position(|w| w == b"\r\n\r\n") {
-                let after = pos + 4;
-                if after < head.len() {
-                    upstream.write_all(&head[after..])?;
+            let mut record = HttpRecord {
+                started: Some(SystemTime::now()),
+                client: peer,
+                method: method.clone(),
+                url: format!("{}://{host}:{port}{path}", scheme.as_str()),
+                request_headers: strip_hop_by_hop(&headers_out, is_upgrade_request(&headers_out)),
+                ..HttpRecord::default()
+            };
+            let checked = match socks_target(&host, cfg) {
+                Ok(checked) => checked,
+                Err(reason) => {
+                    logv(cfg, &format!("{method} {host}:{port} blocked: {reason}"));
+                    return write_error_response(client, 403, "Forbidden", &reason);
                 }
-            }
+            };
+            let t0 = Instant::now();
+            let upstream = connect_upstream(&host, checked, connect_port, cfg)?;
+            record.timings.connect = t0.elapsed();
+            let req = OutgoingRequest {
+                method: &method,
+                path: &path,
+                host: &host,
+                headers: &headers_out,
+                early_body: body_after_head(&head),
+            };
+            let res = if use_tls {
+                forward_tls(client, upstream, &req, &mut record, cfg)
+            } else {
+                forward_http(client, upstream, &req, &mut record, cfg)
+            };
+            capture(cfg, &CaptureRecord::Http(Box::new(record)));
+            res
+        }
+    }
+}
+
+// Туннель после успешного ответа клиенту (HTTP CONNECT или SOCKS5):
+// проверка SNI, перехват или прозрачная передача и запись в --capture.
+fn tunnel<C: Duplex>(
+    client: &mut C,
+    upstream: UpstreamStream,
+    host: String,
+    port: u16,
+    peer: Option<SocketAddr>,
+    (started, t0): (SystemTime, Instant),
+    cfg: &Config,
+) -> io::Result<()> {
+    // Отказать клиенту уже нельзя, поэтому при несовпадении SNI туннель
+    // просто закрывается.
+    if let Some(tcp) = client.as_tcp()
+        && cfg.acl.needs_sni(&host, port)
+        && let ClientHello::Sni(Some(sni)) = peek_client_hello(tcp, SNI_PEEK_TIMEOUT)?
+        && let Err(reason) = cfg.acl.check_sni(&host, port, &sni)
+    {
+        eprintln!("CONNECT {host}:{port} closed: {reason}");
+        return Ok(());
+    }
+    // Для туннелей записываются только метаданные и объём трафика, и
+    // счётчики нужны только при включённом --capture.
+    let counters = cfg.capture.as_ref().map(|_| {
+        (
+            Arc::new(Mutex::new(BodyBuffer::new(0))),
+            Arc::new(Mutex::new(BodyBuffer::new(0))),
+        )
+    });
+    let sinks = counters
+        .as_ref()
+        .map(|(sent, received)| (sent.clone() as Sink, received.clone() as Sink));
+    // Перехват возможен только поверх обычного TCP: внутри
+    // TLS-слушателя туннель остаётся непрозрачным.
+    let mitm_client = if cfg.mitm_hosts.iter().any(|p| p.matches(&host)) {
+        let tcp = client.as_tcp().map(TcpStream::try_clone).transpose()?;
+        if tcp.is_none() {
+            logv(
+                cfg,
+                &format!("MITM skipped for {host}:{port} on TLS listener"),
+            );
+        }
+        tcp
+    } else {
+        None
+    };
+    let res = if let Some(tcp) = mitm_client {
+        intercept(tcp, upstream, &host, port, sinks, cfg)
+    } else {
+        let _ = pipe_counted(client.try_clone()?, upstream, sinks);
+        Ok(())
+    };
+    if let Some((sent, received)) = counters {
+        let record = TunnelRecord {
+            started: Some(started),
+            client: peer,
+            host,
+            port,
+            bytes_sent: sent.lock().map_or(0, |b| b.total),
+            bytes_received: received.lock().map_or(0, |b| b.total),
+            duration: t0.elapsed(),
+        };
+        capture(cfg, &CaptureRecord::Tunnel(record));
+    }
+    res
+}
+
+// Двусторонняя передача; с `sinks` трафик каждой стороны дублируется в них.
+fn pipe_counted<A: Duplex, B: Duplex>(a: A, b: B, sinks: Option<(Sink, Sink)>) -> io::Result<()> {
+    match sinks {
+        Some((a_sink, b_sink)) => pipe_bidirectional(Tap::new(a, a_sink), Tap::new(b, b_sink)),
+        None => pipe_bidirectional(a, b),
+    }
+}
+
+// Ошибка записи в --capture не должна влиять на результат соединения.
+fn capture(cfg: &Config, record: &CaptureRecord) {
+    if let Some(log) = &cfg.capture
+        && let Err(e) = log.record(record)
+    {
+        eprintln!("capture write error: {e}");
+    }
+}
+
+// Расшифровывает туннель: клиенту предъявляется сертификат, выпущенный
+// локальным CA, к серверу устанавливается отдельное TLS-соединение, а
+// открытый трафик обоих направлений пишется в --mitm-dump.
+#[cfg(feature = "mitm")]
+fn intercept(
+    client: TcpStream,
+    upstream: UpstreamStream,
+    host: &str,
+    port: u16,
+    sinks: Option<(Sink, Sink)>,
+    cfg: &Config,
+) -> io::Result<()> {
+    let (Some(ca), Some(tls_client), Some(dir)) = (&cfg.mitm, &cfg.tls_client, &cfg.mitm_dump)
+    else {
+        return Err(io::Error::other("MITM is not configured"));
+    };
+    // Сначала проверяем сертификат настоящего сервера, чтобы не выдать
+    // клиенту валидную подделку для недоверенного хоста.
+    let upstream = tls_connect(upstream, tls_client.clone(), host)?;
+    let client = tls_accept(client, ca.server_config(host)?)?;
+
+    let dump = MitmDump::create(Path::new(dir), host, port)?;
+    logv(
+        cfg,
+        &format!(
+            "MITM {host}:{port} -> {}.{{request,response}}",
+            dump.prefix.display()
+        ),
+    );
+
+    pipe_counted(
+        Tap::new(client, dump.request),
+        Tap::new(upstream, dump.response),
+        sinks,
+    )
+}
+
+#[cfg(not(feature = "mitm"))]
+fn intercept(
+    _client: TcpStream,
+    _upstream: UpstreamStream,
+    _host: &str,
+    _port: u16,
+    _sinks: Option<(Sink, Sink)>,
+    _cfg: &Config,
+) -> io::Result<()> {
+    Err(io::Error::other("MITM inspection is not compiled in"))
+}
+
+// С --block-private имя разрешается локально и проверяется: подключение
+// идёт только к проверенным адресам. Без неё `None`, и имя разрешается
+// по --dns.
+fn socks_target(host: &str, cfg: &Config) -> Result<Option<Vec<IpAddr>>, String> {
+    cfg.ssrf
+        .as_ref()
+        .map(|guard| guard.resolve(host, |name| cfg.dns.lookup(name)))
+        .transpose()
+}
+
+fn connect_upstream(
+    host: &str,
+    checked: Option<Vec<IpAddr>>,
+    port: u16,
+    cfg: &Config,
+) -> io::Result<UpstreamStream> {
+    let mut chain = Vec::with_capacity(1 + cfg.hops.len());
+    chain.push(cfg.upstream.clone());
+    chain.extend(cfg.hops.iter().cloned());
+    let connect = move |target: &str| connect_chain(&chain, target, port);
+    match checked {
+        Some(addrs) => cfg.dns.connect_addrs(host, &addrs, connect),
+        None => cfg.dns.connect(host, connect),
+    }
+}
+
+type Sink = Arc<Mutex<dyn Write + Send>>;
+
+struct OutgoingRequest<'a> {
+    method: &'a str,
+    path: &'a str,
+    host: &'a str,
+    headers: &'a [(String, String)],
+    // Байты тела, прочитанные вместе с заголовком.
+    early_body: &'a [u8],
+}
 
-            let a = client.try_clone()?;
-            let _ = pipe_bidirectional(a, upstream);
-            Ok(())
+#[cfg(feature = "tls")]
+fn forward_tls<C: Duplex>(
+    client: &mut C,
+    upstream: UpstreamStream,
+    req: &OutgoingRequest,
+    record: &mut HttpRecord,
+    cfg: &Config,
+) -> io::Result<()> {
+    let Some(config) = cfg.tls_client.clone() else {
+        return Err(io::Error::other("TLS origination is not configured"));
+    };
+    let t0 = Instant::now();
+    let tls = tls_connect(upstream, config, req.host);
+    record.timings.connect += t0.elapsed();
+    let tls = match tls {
+        Ok(tls) => tls,
+        Err(e) => {
+            logv(cfg, &format!("TLS to {} failed: {e}", req.host));
+            return write_error_response(
+                client,
+                502,
+                "Bad Gateway",
+                &format!("TLS handshake with {} failed: {e}", req.host),
+            );
         }
+    };
+    forward_http(client, tls, req, record, cfg)
+}
+
+#[cfg(not(feature = "tls"))]
+fn forward_tls<C: Duplex>(
+    _client: &mut C,
+    _upstream: UpstreamStream,
+    _req: &OutgoingRequest,
+    _record: &mut HttpRecord,
+    _cfg: &Config,
+) -> io::Result<()> {
+    Err(io::Error::other("TLS origination is not compiled in"))
+}
+
+fn forward_http<C: Duplex, U: Duplex>(
+    client: &mut C,
+    mut upstream: U,
+    req: &OutgoingRequest,
+    record: &mut HttpRecord,
+    cfg: &Config,
+) -> io::Result<()> {
+    let t0 = Instant::now();
+    write_modified_request_head(&mut upstream, req.method, req.path, req.headers)?;
+
+    // Сперва отправим наверх байты, уже прочитанные после CRLFCRLF.
+    let request_body = Arc::new(Mutex::new(BodyBuffer::new(cfg.capture_body_limit)));
+    if !req.early_body.is_empty() {
+        upstream.write_all(req.early_body)?;
+        if let Ok(mut body) = request_body.lock() {
+            body.write_all(req.early_body)?;
+        }
+    }
+    upstream.flush()?;
+    record.timings.send = t0.elapsed();
+
+    let upgrade = is_upgrade_request(req.headers);
+    let res = relay_http(
+        client,
+        upstream,
+        upgrade,
+        req.host,
+        request_body.clone(),
+        record,
+        cfg,
+    );
+    if let Ok(mut body) = request_body.lock() {
+        record.request_body = std::mem::take(&mut *body);
     }
+    res
+}
+
+// Тело запроса пересылается наверх в отдельном потоке, а ответ разбирается,
+// чтобы переписать его заголовки перед отправкой клиенту.
+fn relay_http<C: Duplex, U: Duplex>(
+    client: &mut C,
+    upstream: U,
+    upgrade: bool,
+    host: &str,
+    request_body: Arc<Mutex<BodyBuffer>>,
+    record: &mut HttpRecord,
+    cfg: &Config,
+) -> io::Result<()> {
+    let mut client_r = Tap::new(client.try_clone()?, request_body as Sink);
+    let mut upstream_r = upstream.try_clone()?;
+    let mut upstream_w = upstream;
+    let t = thread::spawn(move || {
+        let _ = io::copy(&mut client_r, &mut upstream_w);
+        let _ = upstream_w.flush();
+        let _ = upstream_w.shutdown_write();
+    });
+
+    let t0 = Instant::now();
+    let response_body = Arc::new(Mutex::new(BodyBuffer::new(cfg.capture_body_limit)));
+    let mut head = Vec::with_capacity(4096);
+    let final_head = read_final_response_head(&mut upstream_r, client, &mut head, |resp| {
+        cfg.header_rules
+            .apply(Direction::Response, host, &mut resp.headers);
+    })?;
+    let early_body = if let Some(resp) = final_head {
+        write_modified_response_head(client, &resp, upgrade)?;
+        record.status = resp.status;
+        record.reason = resp.reason;
+        record.response_version = resp.version;
+        record.response_headers = resp.headers;
+        body_after_head(&head)
+    } else {
+        // Ответ без заголовка: отдаём клиенту то, что успели получить.
+        &head[..]
+    };
+    record.timings.wait = t0.elapsed();
+
+    let t0 = Instant::now();
+    client.write_all(early_body)?;
+    if let Ok(mut body) = response_body.lock() {
+        body.write_all(early_body)?;
+    }
+    let mut upstream_r = Tap::new(upstream_r, response_body.clone() as Sink);
+    let _ = io::copy(&mut upstream_r, client);
+    let _ = client.flush();
+    let _ = client.shutdown_write();
+    record.timings.receive = t0.elapsed();
+    let _ = t.join();
+    if let Ok(mut body) = response_body.lock() {
+        record.response_body = std::mem::take(&mut *body);
+    }
+    Ok(())
 }
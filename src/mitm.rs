@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rcgen::{CertificateParams, DnType, ExtendedKeyUsagePurpose, Issuer, KeyPair};
+use rustls::ServerConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+fn mitm_err<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("MITM CA: {e}"))
+}
+
+// Сколько выпущенных конфигураций держать в кеше по умолчанию.
+pub const MITM_CACHE_ENTRIES: usize = 1024;
+// Срок действия листовых сертификатов; начало сдвинуто назад на случай
+// расхождения часов с клиентом.
+const LEAF_VALIDITY: Duration = Duration::from_hours(30 * 24);
+const LEAF_BACKDATE: Duration = Duration::from_hours(1);
+// Сертификат выпускается заново, если до конца срока осталось меньше суток.
+const LEAF_RENEW: Duration = Duration::from_hours(24);
+
+struct CachedConfig {
+    config: Arc<ServerConfig>,
+    expires: SystemTime,
+    last_used: u64,
+}
+
+// Локальный CA, выпускающий сертификаты для перехватываемых хостов.
+// Все листовые сертификаты используют один ключ, выпущенные конфигурации
+// кешируются по имени хоста; при переполнении вытесняется самая давно
+// использованная.
+pub struct MitmCa {
+    issuer: Issuer<'static, KeyPair>,
+    ca_der: CertificateDer<'static>,
+    leaf_key: KeyPair,
+    cache_limit: usize,
+    cache: Mutex<HashMap<String, CachedConfig>>,
+    // Счётчик обращений к кешу: порядок использования без зависимости от часов.
+    uses: AtomicU64,
+}
+
+impl std::fmt::Debug for MitmCa {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MitmCa").finish_non_exhaustive()
+    }
+}
+
+impl MitmCa {
+    pub fn load(cert_file: &Path, key_file: &Path) -> io::Result<Self> {
+        let cert_pem = fs::read_to_string(cert_file)?;
+        let key = KeyPair::from_pem(&fs::read_to_string(key_file)?).map_err(mitm_err)?;
+        let ca_der = rustls::pki_types::pem::PemObject::from_pem_slice(cert_pem.as_bytes())
+            .map_err(mitm_err)?;
+        let issuer = Issuer::from_ca_cert_pem(&cert_pem, key).map_err(mitm_err)?;
+        Ok(Self {
+            issuer,
+            ca_der,
+            leaf_key: KeyPair::generate().map_err(mitm_err)?,
+            cache_limit: MITM_CACHE_ENTRIES,
+            cache: Mutex::new(HashMap::new()),
+            uses: AtomicU64::new(0),
+        })
+    }
+
+    #[must_use]
+    pub const fn with_cache_limit(mut self, limit: usize) -> Self {
+        self.cache_limit = limit;
+        self
+    }
+
+    // Конфигурация TLS-сервера с сертификатом для `host` (DNS-имя или IP).
+    pub fn server_config(&self, host: &str) -> io::Result<Arc<ServerConfig>> {
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase();
+        let now = SystemTime::now();
+        let tick = self.uses.fetch_add(1, Ordering::Relaxed);
+        if let Some(cached) = self.lock_cache()?.get_mut(&host)
+            && cached.expires > now + LEAF_RENEW
+        {
+            cached.last_used = tick;
+            return Ok(cached.config.clone());
+        }
+
+        let expires = now + LEAF_VALIDITY;
+        let mut params = CertificateParams::new(vec![host.clone()]).map_err(mitm_err)?;
+        params.not_before = (now - LEAF_BACKDATE).into();
+        params.not_after = expires.into();
+        params.distinguished_name.push(DnType::CommonName, &host);
+        params
+            .extended_key_usages
+            .push(ExtendedKeyUsagePurpose::ServerAuth);
+        let cert = params
+            .signed_by(&self.leaf_key, &self.issuer)
+            .map_err(mitm_err)?;
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.leaf_key.serialize_der()));
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .map_err(mitm_err)?
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.der().clone(), self.ca_der.clone()], key)
+                .map_err(mitm_err)?;
+        let config = Arc::new(config);
+        if self.cache_limit == 0 {
+            return Ok(config);
+        }
+        let mut cache = self.lock_cache()?;
+        if cache.len() >= self.cache_limit
+            && !cache.contains_key(&host)
+            && let Some(oldest) = cache
+                .iter()
+                .min_by_key(|(_, c)| c.last_used)
+                .map(|(k, _)| k.clone())
+        {
+            cache.remove(&oldest);
+        }
+        cache.insert(
+            host,
+            CachedConfig {
+                config: config.clone(),
+                expires,
+                last_used: tick,
+            },
+        );
+        drop(cache);
+        Ok(config)
+    }
+
+    #[must_use]
+    pub fn cached(&self) -> usize {
+        self.lock_cache().map_or(0, |c| c.len())
+    }
+
+    fn lock_cache(&self) -> io::Result<MutexGuard<'_, HashMap<String, CachedConfig>>> {
+        self.cache
+            .lock()
+            .map_err(|_| io::Error::other("MITM cache lock poisoned"))
+    }
+}
+
+// Файлы с расшифрованным трафиком одного туннеля: `<prefix>.request`
+// (от клиента) и `<prefix>.response` (от сервера).
+pub struct MitmDump {
+    pub prefix: PathBuf,
+    pub request: Arc<Mutex<dyn Write + Send>>,
+    pub response: Arc<Mutex<dyn Write + Send>>,
+}
+
+impl MitmDump {
+    pub fn create(dir: &Path, host: &str, port: u16) -> io::Result<Self> {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+        let name = host.replace(
+            |c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '-',
+            "_",
+        );
+        let prefix = dir.join(format!("{millis}-{seq}-{name}-{port}"));
+        let file = |ext: &str| {
+            let mut path = prefix.clone().into_os_string();
+            path.push(ext);
+            File::create(path)
+        };
+        let request = file(".request")?;
+        let response = file(".response")?;
+        Ok(Self {
+            prefix,
+            request: Arc::new(Mutex::new(request)),
+            response: Arc::new(Mutex::new(response)),
+        })
+    }
+}
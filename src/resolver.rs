@@ -0,0 +1,537 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::dns::{FamilyPreference, resolve_host};
+use crate::{Socks5Datagram, TargetAddr};
+
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_AAAA: u16 = 28;
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn dns_err(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("DNS: {msg}"))
+}
+
+// Результат разрешения имени. Пустой список адресов — отрицательный ответ,
+// который тоже кешируется на `ttl`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsAnswer {
+    pub addrs: Vec<IpAddr>,
+    pub ttl: Duration,
+}
+
+// Запрос DNS (RFC 1035) с флагом RD для одного имени и типа записи.
+pub fn build_dns_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut q = Vec::with_capacity(18 + name.len());
+    q.extend_from_slice(&id.to_be_bytes());
+    q.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        let len = u8::try_from(label.len())
+            .ok()
+            .filter(|&l| l > 0 && l < 64)
+            .ok_or_else(|| dns_err("bad label in name"))?;
+        q.push(len);
+        q.extend_from_slice(label.as_bytes());
+    }
+    q.push(0);
+    q.extend_from_slice(&qtype.to_be_bytes());
+    q.extend_from_slice(&1u16.to_be_bytes()); // класс IN
+    Ok(q)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos + n;
+        let out = self
+            .data
+            .get(self.pos..end)
+            .ok_or_else(|| dns_err("truncated message"))?;
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.take(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    // Имя нам не нужно, только его длина; сжатое имя заканчивается указателем.
+    fn skip_name(&mut self) -> io::Result<()> {
+        loop {
+            let len = self.take(1)?[0];
+            match len {
+                0 => return Ok(()),
+                l if l & 0xC0 == 0xC0 => {
+                    self.take(1)?;
+                    return Ok(());
+                }
+                l => {
+                    self.take(usize::from(l))?;
+                }
+            }
+        }
+    }
+}
+
+// Вопрос запроса из `build_dns_query`: всё после заголовка.
+fn question(query: &[u8]) -> io::Result<&[u8]> {
+    query
+        .get(12..)
+        .filter(|q| q.len() > 4)
+        .ok_or_else(|| dns_err("malformed query"))
+}
+
+// Совпадают ли id и вопрос ответа с запросом (имя без учёта регистра).
+fn is_reply_to(query: &[u8], msg: &[u8]) -> bool {
+    question(query).is_ok_and(|q| {
+        msg.len() >= query.len()
+            && msg[..2] == query[..2]
+            && msg[4..6] == [0, 1]
+            && msg[12..query.len()].eq_ignore_ascii_case(q)
+    })
+}
+
+// Разбор ответа на `query`: адреса запрошенного типа из раздела answer (в
+// том числе после CNAME) и TTL. Для отрицательного ответа TTL берётся из
+// SOA (RFC 2308), иначе `negative_ttl`.
+pub fn parse_dns_response(
+    query: &[u8],
+    msg: &[u8],
+    negative_ttl: Duration,
+) -> io::Result<DnsAnswer> {
+    let question = question(query)?;
+    let qtype = u16::from_be_bytes([question[question.len() - 4], question[question.len() - 3]]);
+    let mut r = Reader { data: msg, pos: 0 };
+    if r.take(2)? != &query[..2] {
+        return Err(dns_err("response id mismatch"));
+    }
+    let flags = r.u16()?;
+    if flags & 0x8000 == 0 {
+        return Err(dns_err("not a response"));
+    }
+    // Усечённый ответ неполон, кешировать его нельзя.
+    if flags & 0x0200 != 0 {
+        return Err(dns_err("truncated response"));
+    }
+    let rcode = flags & 0x000F;
+    // NOERROR и NXDOMAIN — настоящие ответы, остальное — ошибка сервера.
+    if rcode != 0 && rcode != 3 {
+        return Err(dns_err(&format!("server returned rcode {rcode}")));
+    }
+    let qdcount = r.u16()?;
+    let ancount = r.u16()?;
+    let nscount = r.u16()?;
+    r.u16()?;
+    if qdcount != 1 || !r.take(question.len())?.eq_ignore_ascii_case(question) {
+        return Err(dns_err("response question mismatch"));
+    }
+
+    let mut addrs = Vec::new();
+    let mut ttl: Option<u32> = None;
+    let mut soa_ttl: Option<u32> = None;
+    for i in 0..u32::from(ancount) + u32::from(nscount) {
+        r.skip_name()?;
+        let rtype = r.u16()?;
+        r.u16()?;
+        let rttl = r.u32()?;
+        let len = usize::from(r.u16()?);
+        let rdata = r.take(len)?;
+        let answer = i < u32::from(ancount) && rtype == qtype;
+        let ip = match (rtype, rdata.len()) {
+            (TYPE_A, 4) if answer => Some(IpAddr::from(Ipv4Addr::new(
+                rdata[0], rdata[1], rdata[2], rdata[3],
+            ))),
+            (TYPE_AAAA, 16) if answer => {
+                let mut b = [0u8; 16];
+                b.copy_from_slice(rdata);
+                Some(IpAddr::from(Ipv6Addr::from(b)))
+            }
+            (TYPE_SOA, _) if i >= u32::from(ancount) => {
+                let mut soa = Reader {
+                    data: msg,
+                    pos: r.pos - len,
+                };
+                soa.skip_name()?;
+                soa.skip_name()?;
+                soa.take(16)?;
+                soa_ttl = Some(rttl.min(soa.u32()?));
+                None
+            }
+            _ => None,
+        };
+        if let Some(ip) = ip {
+            addrs.push(ip);
+            ttl = Some(ttl.map_or(rttl, |t| t.min(rttl)));
+        }
+    }
+    let ttl = if addrs.is_empty() {
+        soa_ttl.map_or(negative_ttl, |t| Duration::from_secs(u64::from(t)))
+    } else {
+        Duration::from_secs(u64::from(ttl.unwrap_or(0)))
+    };
+    Ok(DnsAnswer { addrs, ttl })
+}
+
+// Откуда кеш берёт ответы.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsSource {
+    // Системный резолвер; он не сообщает TTL, поэтому используется `ttl`.
+    System {
+        ttl: Duration,
+    },
+    // Запросы A/AAAA по UDP к этому серверу; TTL берётся из ответа.
+    Server(SocketAddr),
+    // То же, но через SOCKS5 UDP ASSOCIATE, чтобы запросы шли через туннель.
+    Socks {
+        socks_addr: String,
+        user: Option<String>,
+        pass: Option<String>,
+        server: SocketAddr,
+    },
+}
+
+impl DnsSource {
+    // `idle` — свободные UDP-ассоциации для `Socks`, общие для всех поисков.
+    fn resolve(
+        &self,
+        name: &str,
+        family: FamilyPreference,
+        negative_ttl: Duration,
+        idle: &Mutex<Vec<Socks5Datagram>>,
+    ) -> io::Result<DnsAnswer> {
+        match self {
+            // Системный резолвер не отличает NXDOMAIN от сбоя сети.
+            Self::System { ttl } => Ok(resolve_host(name).map_or_else(
+                |_| DnsAnswer {
+                    addrs: Vec::new(),
+                    ttl: negative_ttl,
+                },
+                |addrs| DnsAnswer { addrs, ttl: *ttl },
+            )),
+            Self::Server(server) => {
+                let bind: SocketAddr = if server.is_ipv4() {
+                    (Ipv4Addr::UNSPECIFIED, 0).into()
+                } else {
+                    (Ipv6Addr::UNSPECIFIED, 0).into()
+                };
+                let socket = UdpSocket::bind(bind)?;
+                socket.connect(server)?;
+                query_both(name, family, negative_ttl, |query| {
+                    socket.send(query)?;
+                    await_reply(query, |buf, left| {
+                        socket.set_read_timeout(Some(left))?;
+                        socket.recv(buf).map(Some)
+                    })
+                })
+            }
+            Self::Socks {
+                socks_addr,
+                user,
+                pass,
+                server,
+            } => {
+                // Новая ассоциация стоит двух обменов с SOCKS-сервером,
+                // поэтому они переиспользуются, пока сервер их не закрыл.
+                let reused = idle
+                    .lock()
+                    .ok()
+                    .and_then(|mut pool| std::iter::from_fn(|| pool.pop()).find(association_alive));
+                let udp = match reused {
+                    Some(udp) => udp,
+                    None => {
+                        Socks5Datagram::associate(socks_addr, user.as_deref(), pass.as_deref())?
+                    }
+                };
+                let server = TargetAddr::Ip(*server);
+                let answer = query_both(name, family, negative_ttl, |query| {
+                    udp.send_to(query, &server)?;
+                    // Датаграммы не от резолвера пропускаем.
+                    await_reply(query, |buf, left| {
+                        udp.set_read_timeout(Some(left))?;
+                        let (n, from) = udp.recv_from(buf)?;
+                        Ok((from == server).then_some(n))
+                    })
+                })?;
+                // После ошибки ассоциация не возвращается: следующий поиск
+                // откроет новую.
+                if let Ok(mut pool) = idle.lock()
+                    && pool.len() < SOCKS_DNS_IDLE
+                {
+                    pool.push(udp);
+                }
+                Ok(answer)
+            }
+        }
+    }
+}
+
+// Сколько свободных UDP-ассоциаций DNS через SOCKS держать открытыми.
+const SOCKS_DNS_IDLE: usize = 4;
+
+// Ассоциация жива, пока сервер не закрыл управляющее соединение.
+fn association_alive(udp: &Socks5Datagram) -> bool {
+    let control = udp.control();
+    if control.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let alive = matches!(
+        control.peek(&mut [0u8; 1]),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock
+    );
+    control.set_nonblocking(false).is_ok() && alive
+}
+
+// Ждёт ответ на `query` не дольше DNS_TIMEOUT. `recv` читает одну датаграмму
+// с заданным таймаутом (`None` — не от резолвера); ответы с чужим id или
+// вопросом, например запоздавшие на прошлый запрос, пропускаются.
+fn await_reply<F>(query: &[u8], mut recv: F) -> io::Result<Vec<u8>>
+where
+    F: FnMut(&mut [u8], Duration) -> io::Result<Option<usize>>,
+{
+    let deadline = Instant::now() + DNS_TIMEOUT;
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "DNS: no reply from server",
+            ));
+        }
+        if let Some(n) = recv(&mut buf, left)?
+            && is_reply_to(query, &buf[..n])
+        {
+            buf.truncate(n);
+            return Ok(buf);
+        }
+    }
+}
+
+// Запрашивает A и AAAA (только нужные `family`) через `exchange` (запрос ->
+// ответ) и объединяет ответы. Ошибка одного запроса не отменяет адреса из
+// другого; ошибкой считается только отсутствие адресов при сбое.
+pub fn query_both<F>(
+    name: &str,
+    family: FamilyPreference,
+    negative_ttl: Duration,
+    mut exchange: F,
+) -> io::Result<DnsAnswer>
+where
+    F: FnMut(&[u8]) -> io::Result<Vec<u8>>,
+{
+    let qtypes: &[u16] = match family {
+        FamilyPreference::Ipv4Only => &[TYPE_A],
+        FamilyPreference::Ipv6Only => &[TYPE_AAAA],
+        _ => &[TYPE_A, TYPE_AAAA],
+    };
+    let mut addrs = Vec::new();
+    let mut ttl: Option<Duration> = None;
+    let mut neg_ttl = negative_ttl;
+    let mut failure = None;
+    for &qtype in qtypes {
+        let query = build_dns_query(next_query_id(), name, qtype)?;
+        let answer = match exchange(&query)
+            .and_then(|reply| parse_dns_response(&query, &reply, negative_ttl))
+        {
+            Ok(answer) => answer,
+            Err(e) => {
+                failure = Some(e);
+                continue;
+            }
+        };
+        if answer.addrs.is_empty() {
+            neg_ttl = neg_ttl.min(answer.ttl);
+        } else {
+            ttl = Some(ttl.map_or(answer.ttl, |t| t.min(answer.ttl)));
+            addrs.extend(answer.addrs);
+        }
+    }
+    if let Some(e) = failure
+        && addrs.is_empty()
+    {
+        return Err(e);
+    }
+    Ok(DnsAnswer {
+        addrs,
+        ttl: ttl.unwrap_or(neg_ttl),
+    })
+}
+
+// Id должен быть непредсказуем, иначе ответ легко подделать: счётчик и
+// время хешируются со случайным ключом `RandomState`.
+fn next_query_id() -> u16 {
+    use std::hash::{BuildHasher, Hasher, RandomState};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(NEXT.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos()),
+    );
+    u16::try_from(hasher.finish() & 0xFFFF).unwrap_or(0)
+}
+
+// Файл в формате /etc/hosts: `адрес имя [имя...]`, `#` — комментарий.
+pub fn parse_hosts(text: &str) -> io::Result<Vec<(String, IpAddr)>> {
+    let mut out = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut words = line.split_whitespace();
+        let Some(addr) = words.next() else {
+            continue;
+        };
+        let ip = addr.parse::<IpAddr>().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("line {}: bad address {addr}", i + 1),
+            )
+        })?;
+        out.extend(words.map(|name| (name.to_owned(), ip)));
+    }
+    Ok(out)
+}
+
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+// Кеш разрешения имён с учётом TTL и отрицательных ответов. Статические
+// записи (`hosts`) имеют приоритет и не истекают.
+pub struct DnsCache {
+    source: DnsSource,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    max_entries: usize,
+    negative_ttl: Duration,
+    family: FamilyPreference,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    idle: Mutex<Vec<Socks5Datagram>>,
+}
+
+impl std::fmt::Debug for DnsCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DnsCache")
+            .field("source", &self.source)
+            .field("max_entries", &self.max_entries)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DnsCache {
+    #[must_use]
+    pub fn new(source: DnsSource, max_entries: usize, negative_ttl: Duration) -> Self {
+        Self {
+            source,
+            hosts: HashMap::new(),
+            max_entries,
+            negative_ttl,
+            family: FamilyPreference::Any,
+            entries: Mutex::new(HashMap::new()),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Семейства, которые запрашиваются у DNS-сервера: при ipv4 или ipv6
+    // запрос другого типа не отправляется.
+    pub const fn set_family(&mut self, family: FamilyPreference) {
+        self.family = family;
+    }
+
+    pub fn add_host(&mut self, name: &str, ip: IpAddr) {
+        let addrs = self
+            .hosts
+            .entry(name.trim_end_matches('.').to_ascii_lowercase())
+            .or_default();
+        if !addrs.contains(&ip) {
+            addrs.push(ip);
+        }
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, HashMap<String, CacheEntry>>> {
+        self.entries
+            .lock()
+            .map_err(|_| io::Error::other("DNS cache lock poisoned"))
+    }
+
+    pub fn lookup(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        let key = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(addrs) = self.hosts.get(&key) {
+            return Ok(addrs.clone());
+        }
+        let now = Instant::now();
+        let cached = self
+            .lock()?
+            .get(&key)
+            .filter(|e| e.expires > now)
+            .map(|e| e.addrs.clone());
+        let addrs = if let Some(addrs) = cached {
+            addrs
+        } else {
+            let answer = self
+                .source
+                .resolve(&key, self.family, self.negative_ttl, &self.idle)?;
+            self.store(key, &answer, now)?;
+            answer.addrs
+        };
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("cannot resolve {name}"),
+            ));
+        }
+        Ok(addrs)
+    }
+
+    fn store(&self, key: String, answer: &DnsAnswer, now: Instant) -> io::Result<()> {
+        if self.max_entries == 0 || answer.ttl.is_zero() {
+            return Ok(());
+        }
+        let mut entries = self.lock()?;
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            // Сначала выбрасываем истёкшие записи, затем ближайшую к истечению.
+            entries.retain(|_, e| e.expires > now);
+            if entries.len() >= self.max_entries
+                && let Some(oldest) = entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.expires)
+                    .map(|(k, _)| k.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            CacheEntry {
+                addrs: answer.addrs.clone(),
+                expires: now + answer.ttl,
+            },
+        );
+        drop(entries);
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.lock().map_or(0, |e| e.len())
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
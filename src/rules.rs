@@ -0,0 +1,191 @@
+use std::io;
+use std::str::FromStr;
+
+// Шаблон имени хоста: `*` — любой хост, `*.example.com` — поддомены
+// example.com, иначе точное совпадение (без учёта регистра). `Domain` —
+// домен вместе с поддоменами; в этом синтаксисе его нет, он нужен правилам
+// `domain:` в ACL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    Any,
+    Subdomains(String),
+    Domain(String),
+    Exact(String),
+}
+
+fn is_subdomain(host: &str, suffix: &str) -> bool {
+    host.len() > suffix.len() + 1
+        && host.as_bytes()[host.len() - suffix.len() - 1] == b'.'
+        && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+}
+
+impl HostPattern {
+    #[must_use]
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        match self {
+            Self::Any => true,
+            Self::Subdomains(suffix) => is_subdomain(host, suffix),
+            Self::Domain(name) => host.eq_ignore_ascii_case(name) || is_subdomain(host, name),
+            Self::Exact(name) => host.eq_ignore_ascii_case(name),
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().trim_end_matches('.');
+        if s.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty host pattern",
+            ));
+        }
+        if s == "*" {
+            return Ok(Self::Any);
+        }
+        if let Some(suffix) = s.strip_prefix("*.") {
+            return Ok(Self::Subdomains(suffix.to_ascii_lowercase()));
+        }
+        Ok(Self::Exact(s.to_ascii_lowercase()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderAction {
+    // Добавить заголовок, не трогая существующие с тем же именем.
+    Add(String, String),
+    // Заменить все заголовки с этим именем одним значением.
+    Set(String, String),
+    Remove(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderRule {
+    pub direction: Direction,
+    pub host: HostPattern,
+    pub action: HeaderAction,
+}
+
+fn invalid_rule(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_owned())
+}
+
+// Формат правила: `<request|response> <host-pattern> <add|set|remove> Name[: value]`,
+// например `request *.example.com set User-Agent: curl/8.0`.
+impl FromStr for HeaderRule {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s.trim();
+        let mut word = || {
+            let (w, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            rest = tail.trim_start();
+            w
+        };
+        let direction = match word().to_ascii_lowercase().as_str() {
+            "request" | "req" => Direction::Request,
+            "response" | "resp" => Direction::Response,
+            _ => return Err(invalid_rule("rule must start with request or response")),
+        };
+        let host = word().parse::<HostPattern>()?;
+        let verb = word().to_ascii_lowercase();
+        let (name, value) = match rest.split_once(':') {
+            Some((n, v)) => (n.trim(), Some(v.trim())),
+            None => (rest.trim(), None),
+        };
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(invalid_rule("bad header name in rule"));
+        }
+        let action = match (verb.as_str(), value) {
+            ("add", Some(v)) => HeaderAction::Add(name.to_owned(), v.to_owned()),
+            ("set" | "replace", Some(v)) => HeaderAction::Set(name.to_owned(), v.to_owned()),
+            ("remove", None) => HeaderAction::Remove(name.to_owned()),
+            ("add" | "set" | "replace", None) => {
+                return Err(invalid_rule("rule needs Name: value"));
+            }
+            ("remove", Some(_)) => return Err(invalid_rule("remove takes only a header name")),
+            _ => return Err(invalid_rule("rule action must be add, set or remove")),
+        };
+        Ok(Self {
+            direction,
+            host,
+            action,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderRules {
+    rules: Vec<HeaderRule>,
+}
+
+impl HeaderRules {
+    pub fn push(&mut self, rule: HeaderRule) {
+        self.rules.push(rule);
+    }
+
+    pub fn append(&mut self, mut other: Self) {
+        self.rules.append(&mut other.rules);
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // Разбор файла правил: одно правило на строку, `#` — комментарий.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut out = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = line.parse::<HeaderRule>().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("line {}: {e}", i + 1))
+            })?;
+            out.push(rule);
+        }
+        Ok(out)
+    }
+
+    // Правила применяются по порядку, поэтому более поздние видят результат
+    // более ранних.
+    pub fn apply(&self, direction: Direction, host: &str, headers: &mut Vec<(String, String)>) {
+        for rule in &self.rules {
+            if rule.direction != direction || !rule.host.matches(host) {
+                continue;
+            }
+            match &rule.action {
+                HeaderAction::Add(name, value) => headers.push((name.clone(), value.clone())),
+                HeaderAction::Set(name, value) => {
+                    if let Some(pos) = headers
+                        .iter()
+                        .position(|(k, _)| k.eq_ignore_ascii_case(name))
+                    {
+                        headers[pos] = (name.clone(), value.clone());
+                        let mut i = 0;
+                        headers.retain(|(k, _)| {
+                            i += 1;
+                            i - 1 == pos || !k.eq_ignore_ascii_case(name)
+                        });
+                    } else {
+                        headers.push((name.clone(), value.clone()));
+                    }
+                }
+                HeaderAction::Remove(name) => {
+                    headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+                }
+            }
+        }
+    }
+}
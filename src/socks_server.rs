@@ -0,0 +1,129 @@
+use std::io::{self, Read, Write};
+
+use crate::{TargetAddr, push_socks5_addr, read_socks5_addr};
+
+// Запрос клиента SOCKS5: команда (0x01 CONNECT, 0x02 BIND, 0x03 UDP
+// ASSOCIATE) и адрес назначения.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Request {
+    pub command: u8,
+    pub target: TargetAddr,
+}
+
+// Приветствие клиента: список предлагаемых методов аутентификации.
+pub fn socks5_read_greeting<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut hdr = [0u8; 2];
+    r.read_exact(&mut hdr)?;
+    if hdr[0] != 0x05 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "SOCKS5 bad version in greeting",
+        ));
+    }
+    let mut methods = vec![0u8; usize::from(hdr[1])];
+    r.read_exact(&mut methods)?;
+    Ok(methods)
+}
+
+// Выбор метода; 0xFF — ни один из предложенных не подходит.
+pub fn socks5_select_method<W: Write>(w: &mut W, method: u8) -> io::Result<()> {
+    w.write_all(&[0x05, method])?;
+    w.flush()
+}
+
+// Имя и пароль по RFC 1929.
+pub fn socks5_read_user_pass<R: Read>(r: &mut R) -> io::Result<(String, String)> {
+    let mut ver = [0u8; 1];
+    r.read_exact(&mut ver)?;
+    if ver[0] != 0x01 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "SOCKS5 bad auth version",
+        ));
+    }
+    let mut field = || -> io::Result<String> {
+        let mut len = [0u8; 1];
+        r.read_exact(&mut len)?;
+        let mut buf = vec![0u8; usize::from(len[0])];
+        r.read_exact(&mut buf)?;
+        String::from_utf8(buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid utf8 in SOCKS5 auth"))
+    };
+    let user = field()?;
+    let pass = field()?;
+    Ok((user, pass))
+}
+
+pub fn socks5_auth_status<W: Write>(w: &mut W, ok: bool) -> io::Result<()> {
+    // Любой ненулевой статус означает отказ.
+    w.write_all(&[0x01, u8::from(!ok)])?;
+    w.flush()
+}
+
+pub fn socks5_read_request<R: Read>(r: &mut R) -> io::Result<Socks5Request> {
+    let mut hdr = [0u8; 4];
+    r.read_exact(&mut hdr)?;
+    if hdr[0] != 0x05 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "SOCKS5 bad version in request",
+        ));
+    }
+    let target = read_socks5_addr(r, hdr[3])?;
+    Ok(Socks5Request {
+        command: hdr[1],
+        target,
+    })
+}
+
+// Ответ на запрос: код (RFC 1928, раздел 6) и адрес BND.
+pub fn socks5_write_reply<W: Write>(w: &mut W, reply: u8, bound: &TargetAddr) -> io::Result<()> {
+    let mut buf = vec![0x05, reply, 0x00];
+    push_socks5_addr(&mut buf, bound)?;
+    w.write_all(&buf)?;
+    w.flush()
+}
+
+// Код ответа для ошибки подключения к назначению.
+#[must_use]
+pub fn socks5_reply_code(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => 0x02,
+        io::ErrorKind::NetworkUnreachable => 0x03,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NotFound => 0x04,
+        io::ErrorKind::ConnectionRefused => 0x05,
+        io::ErrorKind::TimedOut => 0x06,
+        io::ErrorKind::Unsupported => 0x07,
+        _ => 0x01,
+    }
+}
+
+// Серверная сторона рукопожатия до запроса включительно. С `credentials`
+// клиент обязан пройти RFC 1929, иначе принимается только 0x00.
+pub fn socks5_accept<S: Read + Write>(
+    s: &mut S,
+    credentials: Option<(&str, &str)>,
+) -> io::Result<Socks5Request> {
+    let methods = socks5_read_greeting(s)?;
+    let method = if credentials.is_some() { 0x02 } else { 0x00 };
+    if !methods.contains(&method) {
+        socks5_select_method(s, 0xFF)?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 client offered no acceptable auth",
+        ));
+    }
+    socks5_select_method(s, method)?;
+    if let Some((user, pass)) = credentials {
+        let (u, p) = socks5_read_user_pass(s)?;
+        let ok = u == user && p == pass;
+        socks5_auth_status(s, ok)?;
+        if !ok {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS5 client auth failed",
+            ));
+        }
+    }
+    socks5_read_request(s)
+}
@@ -0,0 +1,102 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Шаг опроса сокета, когда данные ждут без блокирующего чтения.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// Поток, который можно разделить на чтение и запись в разных потоках,
+// как `TcpStream::try_clone`.
+pub trait Duplex: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown_write(&self) -> io::Result<()>;
+
+    // Исходный сокет, если поток — обычное TCP-соединение без обёрток.
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl Duplex for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Self::try_clone(self)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+// Копирует всё прочитанное из потока в `sink` (запись трафика для отладки).
+// Ошибки записи в `sink` не влияют на сам поток.
+pub struct Tap<D> {
+    inner: D,
+    sink: Arc<Mutex<dyn Write + Send>>,
+}
+
+impl<D: Duplex> Tap<D> {
+    pub fn new(inner: D, sink: Arc<Mutex<dyn Write + Send>>) -> Self {
+        Self { inner, sink }
+    }
+}
+
+impl<D: Duplex> Read for Tap<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0
+            && let Ok(mut sink) = self.sink.lock()
+        {
+            let _ = sink.write_all(&buf[..n]);
+            let _ = sink.flush();
+        }
+        Ok(n)
+    }
+}
+
+impl<D: Duplex> Write for Tap<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<D: Duplex> Duplex for Tap<D> {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            inner: self.inner.try_clone()?,
+            sink: Arc::clone(&self.sink),
+        })
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.inner.shutdown_write()
+    }
+}
+
+// Пересылка данных в обе стороны до закрытия обоих направлений.
+pub fn pipe_bidirectional<A: Duplex, B: Duplex>(mut a: A, mut b: B) -> io::Result<()> {
+    let mut ar = a.try_clone()?;
+    let mut br = b.try_clone()?;
+    let t1 = thread::spawn(move || {
+        let _ = io::copy(&mut ar, &mut b);
+        let _ = b.flush();
+        let _ = b.shutdown_write();
+    });
+    let t2 = thread::spawn(move || {
+        let _ = io::copy(&mut br, &mut a);
+        let _ = a.flush();
+        let _ = a.shutdown_write();
+    });
+    let _ = t1.join();
+    let _ = t2.join();
+    Ok(())
+}
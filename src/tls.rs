@@ -0,0 +1,389 @@
+use std::io::{self, Read, Write};
+use std::ops::DerefMut;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, ConnectionCommon, DigitallySignedStruct, RootCertStore,
+    ServerConfig, ServerConnection, SideData, SignatureScheme,
+};
+
+use crate::stream::Duplex;
+
+pub type TlsClientStream<T> = TlsStream<ClientConnection, T>;
+pub type TlsServerStream<T> = TlsStream<ServerConnection, T>;
+
+fn tls_err<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("TLS: {e}"))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(tls_err)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(tls_err)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificates in {}", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+// Набор корневых сертификатов: из PEM-файла, либо встроенный (Mozilla).
+fn root_store(ca_file: Option<&Path>) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(tls_err)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    Ok(roots)
+}
+
+pub fn tls_client_config(ca_file: Option<&Path>) -> io::Result<Arc<ClientConfig>> {
+    tls_upstream_config(ca_file, None, false)
+}
+
+// Общие настройки TLS звена цепочки. Сравниваются по указателю: у
+// `ClientConfig` нет `PartialEq`, а одна конфигурация разделяется
+// всеми socks5+tls звеньями.
+#[derive(Debug, Clone)]
+pub struct UpstreamTlsConfig(pub Arc<ClientConfig>);
+
+impl PartialEq for UpstreamTlsConfig {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for UpstreamTlsConfig {}
+
+// Настройки TLS до вышестоящего сервера: свой CA, клиентский сертификат
+// (`(cert, key)`, оба в PEM) и, при `insecure`, отказ от проверки
+// сертификата сервера.
+pub fn tls_upstream_config(
+    ca_file: Option<&Path>,
+    client_auth: Option<(&Path, &Path)>,
+    insecure: bool,
+) -> io::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_err)?;
+    let builder = if insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertVerification(provider())))
+    } else {
+        builder.with_root_certificates(root_store(ca_file)?)
+    };
+    let config = match client_auth {
+        Some((cert_file, key_file)) => {
+            let key = PrivateKeyDer::from_pem_file(key_file).map_err(tls_err)?;
+            builder
+                .with_client_auth_cert(load_certs(cert_file)?, key)
+                .map_err(tls_err)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+// Принимает любой сертификат сервера. Подписи рукопожатия всё равно
+// проверяются, так что сессия остаётся зашифрованной, но без защиты
+// от подмены сервера.
+#[derive(Debug)]
+struct NoCertVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoCertVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Устанавливает TLS поверх готового транспорта (например, туннеля SOCKS).
+// `server_name` используется и для SNI, и для проверки сертификата; для
+// IP-адресов SNI не отправляется.
+pub fn tls_connect<T: Duplex>(
+    transport: T,
+    config: Arc<ClientConfig>,
+    server_name: &str,
+) -> io::Result<TlsClientStream<T>> {
+    let name = server_name.trim_start_matches('[').trim_end_matches(']');
+    let name = ServerName::try_from(name.to_owned())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid TLS server name"))?;
+    let conn = ClientConnection::new(config, name).map_err(tls_err)?;
+    // Рукопожатие проводим сразу, чтобы ошибка сертификата проявилась
+    // до отправки запроса.
+    TlsStream::handshake(conn, transport)
+}
+
+// Сертификат и ключ слушателя; при `client_ca` клиент обязан предъявить
+// сертификат, подписанный этим CA.
+pub fn tls_server_config(
+    cert_file: &Path,
+    key_file: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(tls_err)?;
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_err)?;
+    let builder = match client_ca {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(root_store(Some(path))?),
+                provider(),
+            )
+            .build()
+            .map_err(tls_err)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs, key).map_err(tls_err)?;
+    Ok(Arc::new(config))
+}
+
+pub fn tls_accept<T: Duplex>(
+    transport: T,
+    config: Arc<ServerConfig>,
+) -> io::Result<TlsServerStream<T>> {
+    let conn = ServerConnection::new(config).map_err(tls_err)?;
+    TlsStream::handshake(conn, transport)
+}
+
+// Сторона чтения: нижний поток и шифротекст, ещё не отданный rustls.
+struct Inbound<T> {
+    sock: T,
+    pending: Vec<u8>,
+    eof: bool,
+}
+
+struct Shared<C, T> {
+    conn: Mutex<C>,
+    inbound: Mutex<Inbound<T>>,
+    // Клон нижнего потока для записи; мьютекс сохраняет порядок TLS-записей.
+    outbound: Mutex<T>,
+}
+
+// TLS-сессия, которую можно читать и писать из разных потоков, как
+// `TcpStream::try_clone`. Блокирующий ввод-вывод идёт по нижнему потоку без
+// блокировки сессии: под ней выполняются только шифрование и разбор записей,
+// поэтому писатель, ждущий места в буфере сокета, не задерживает читателя.
+pub struct TlsStream<C, T> {
+    shared: Arc<Shared<C, T>>,
+}
+
+fn lock<X>(m: &Mutex<X>) -> io::Result<MutexGuard<'_, X>> {
+    m.lock()
+        .map_err(|_| io::Error::other("TLS stream lock poisoned"))
+}
+
+impl<C, S, T> TlsStream<C, T>
+where
+    C: DerefMut<Target = ConnectionCommon<S>> + Send + 'static,
+    S: SideData,
+    T: Duplex,
+{
+    fn handshake(mut conn: C, mut sock: T) -> io::Result<Self> {
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+        let inbound = Inbound {
+            sock: sock.try_clone()?,
+            pending: Vec::new(),
+            eof: false,
+        };
+        Ok(Self {
+            shared: Arc::new(Shared {
+                conn: Mutex::new(conn),
+                inbound: Mutex::new(inbound),
+                outbound: Mutex::new(sock),
+            }),
+        })
+    }
+
+    // Отправляет накопленные TLS-записи. Без `wait` уступает, если сейчас
+    // пишет другой поток: он проверит очередь после своей записи.
+    fn send_pending(&self, wait: bool) -> io::Result<()> {
+        loop {
+            let mut sock = if wait {
+                lock(&self.shared.outbound)?
+            } else {
+                match self.shared.outbound.try_lock() {
+                    Ok(sock) => sock,
+                    Err(TryLockError::WouldBlock) => return Ok(()),
+                    Err(TryLockError::Poisoned(_)) => {
+                        return Err(io::Error::other("TLS stream lock poisoned"));
+                    }
+                }
+            };
+            loop {
+                let mut out = Vec::new();
+                let mut conn = lock(&self.shared.conn)?;
+                while conn.wants_write() {
+                    conn.write_tls(&mut out)?;
+                }
+                drop(conn);
+                if out.is_empty() {
+                    break;
+                }
+                sock.write_all(&out)?;
+            }
+            sock.flush()?;
+            drop(sock);
+            if !lock(&self.shared.conn)?.wants_write() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<C, S, T> Read for TlsStream<C, T>
+where
+    C: DerefMut<Target = ConnectionCommon<S>> + Send + 'static,
+    S: SideData,
+    T: Duplex,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // Читатель один за раз: шифротекст должен попадать в сессию по порядку.
+        let mut inbound = lock(&self.shared.inbound)?;
+        loop {
+            {
+                let mut conn = lock(&self.shared.conn)?;
+                match conn.reader().read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    other => return other,
+                }
+                if inbound.eof {
+                    return Ok(0);
+                }
+                if !inbound.pending.is_empty() {
+                    let pending = &mut inbound.pending;
+                    let mut rd = pending.as_slice();
+                    conn.read_tls(&mut rd)?;
+                    let consumed = pending.len() - rd.len();
+                    pending.drain(..consumed);
+                    let res = conn.process_new_packets().map_err(tls_err);
+                    drop(conn);
+                    // Ответы сессии (alert, KeyUpdate) уходят без ожидания писателя.
+                    self.send_pending(false)?;
+                    res?;
+                    continue;
+                }
+            }
+            let mut chunk = [0u8; 16 * 1024];
+            let n = inbound.sock.read(&mut chunk)?;
+            if n == 0 {
+                inbound.eof = true;
+                let mut conn = lock(&self.shared.conn)?;
+                conn.read_tls(&mut io::empty())?;
+                let res = conn.process_new_packets().map_err(tls_err);
+                drop(conn);
+                res?;
+                continue;
+            }
+            inbound.pending.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<C, S, T> Write for TlsStream<C, T>
+where
+    C: DerefMut<Target = ConnectionCommon<S>> + Send + 'static,
+    S: SideData,
+    T: Duplex,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = lock(&self.shared.conn)?.writer().write(buf)?;
+        self.send_pending(true)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_pending(true)
+    }
+}
+
+impl<C, S, T> Duplex for TlsStream<C, T>
+where
+    C: DerefMut<Target = ConnectionCommon<S>> + Send + 'static,
+    S: SideData,
+    T: Duplex,
+{
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            shared: Arc::clone(&self.shared),
+        })
+    }
+
+    // Закрывает передачу с close_notify.
+    fn shutdown_write(&self) -> io::Result<()> {
+        lock(&self.shared.conn)?.send_close_notify();
+        self.send_pending(true)?;
+        lock(&self.shared.outbound)?.shutdown_write()
+    }
+}
+
+impl<C, T> std::fmt::Debug for TlsStream<C, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsStream").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,283 @@
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use http2socks_proxy::{
+    Acl, AclRule, Cidr, ClientFilter, ClientHello, SsrfGuard, authority_host, client_hello_sni,
+    is_private_ip, peek_client_hello, resolve_host,
+};
+
+fn acl(rules: &[&str]) -> Acl {
+    let mut acl = Acl::default();
+    for rule in rules {
+        acl.push(rule.parse().unwrap());
+    }
+    acl
+}
+
+#[test]
+fn first_matching_rule_wins() {
+    let acl = acl(&[
+        "allow domain:ok.example.com",
+        "deny domain:example.com",
+        "deny ip:10.0.0.0/8 port:22",
+        "deny port:25",
+    ]);
+    assert!(acl.check("example.com", 80).is_err());
+    assert!(acl.check("WWW.Example.COM.", 80).is_err());
+    assert!(acl.check("a.ok.example.com", 80).is_ok());
+    assert!(acl.check("notexample.com", 80).is_ok());
+    assert!(acl.check("10.1.2.3", 22).is_err());
+    assert!(acl.check("10.1.2.3", 80).is_ok());
+    // Правила ip: не разрешают имена.
+    assert!(acl.check("ten.example.net", 22).is_ok());
+    assert!(acl.check(".example.com", 80).is_ok());
+    assert!(acl.check("mail.test", 25).is_err());
+
+    let reason = acl.check("example.com", 80).unwrap_err();
+    assert!(reason.contains("deny domain:example.com"), "{reason}");
+
+    let allow_list = self::acl(&["allow domain:internal.test", "deny all"]);
+    assert!(allow_list.check("api.internal.test", 443).is_ok());
+    assert!(allow_list.check("example.org", 443).is_err());
+}
+
+#[test]
+fn connect_ports() {
+    let mut acl = Acl::default();
+    assert!(acl.check_connect("example.com", 443).is_ok());
+    assert!(acl.check_connect("example.com", 22).is_err());
+    acl.allow_connect_port(8443);
+    assert!(acl.check_connect("example.com", 8443).is_ok());
+}
+
+#[test]
+fn cidr_ranges() {
+    let v4: Cidr = "192.168.0.0/16".parse().unwrap();
+    assert!(v4.contains("192.168.10.1".parse().unwrap()));
+    assert!(v4.contains("::ffff:192.168.10.1".parse().unwrap()));
+    assert!(!v4.contains("192.169.0.1".parse().unwrap()));
+    let v6: Cidr = "fd00::/8".parse().unwrap();
+    assert!(v6.contains("fd12::1".parse().unwrap()));
+    assert!(!v6.contains("fe80::1".parse().unwrap()));
+    let any: Cidr = "0.0.0.0/0".parse().unwrap();
+    assert!(any.contains("8.8.8.8".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!(acl(&["deny ip:::1"]).check("[::1]", 443).is_err());
+}
+
+#[test]
+fn rule_parse_errors() {
+    assert!("block domain:x".parse::<AclRule>().is_err());
+    assert!("deny".parse::<AclRule>().is_err());
+    assert!("deny port:90-80".parse::<AclRule>().is_err());
+    assert!("deny host:x".parse::<AclRule>().is_err());
+    let err = Acl::default()
+        .parse_rules("# comment\nallow all\ndeny ip:nope\n")
+        .unwrap_err();
+    assert!(err.to_string().starts_with("line 3:"), "{err}");
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn regex_rules() {
+    let acl = acl(&[r"deny regex:^ads?\d*\."]);
+    assert!(acl.check("ads3.example.com", 443).is_err());
+    assert!(acl.check("Ad.example.com", 443).is_err());
+    assert!(acl.check("example.com", 443).is_ok());
+}
+
+#[test]
+fn authority_hosts() {
+    assert_eq!(authority_host("example.com:8080"), "example.com");
+    assert_eq!(authority_host("example.com"), "example.com");
+    assert_eq!(authority_host("[::1]:80"), "::1");
+    assert_eq!(authority_host("::1"), "::1");
+}
+
+// Минимальный ClientHello с расширением server_name.
+fn client_hello(sni: Option<&str>) -> Vec<u8> {
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0; 32]);
+    body.push(0); // session id
+    body.extend_from_slice(&[0, 2, 0x13, 0x01]);
+    body.extend_from_slice(&[1, 0]);
+    let mut ext = vec![0x00, 0x2b, 0, 3, 2, 3, 4]; // supported_versions
+    if let Some(name) = sni {
+        let n = u16::try_from(name.len()).unwrap();
+        ext.extend_from_slice(&[0, 0]);
+        ext.extend_from_slice(&(n + 5).to_be_bytes());
+        ext.extend_from_slice(&(n + 3).to_be_bytes());
+        ext.push(0);
+        ext.extend_from_slice(&n.to_be_bytes());
+        ext.extend_from_slice(name.as_bytes());
+    }
+    body.extend_from_slice(&u16::try_from(ext.len()).unwrap().to_be_bytes());
+    body.extend_from_slice(&ext);
+
+    let mut hs = vec![1];
+    hs.extend_from_slice(&u32::try_from(body.len()).unwrap().to_be_bytes()[1..]);
+    hs.extend_from_slice(&body);
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&u16::try_from(hs.len()).unwrap().to_be_bytes());
+    record.extend_from_slice(&hs);
+    record
+}
+
+#[test]
+fn sni_from_client_hello() {
+    let hello = client_hello(Some("example.com"));
+    assert_eq!(
+        client_hello_sni(&hello),
+        ClientHello::Sni(Some("example.com".to_owned()))
+    );
+    assert_eq!(
+        client_hello_sni(&client_hello(None)),
+        ClientHello::Sni(None)
+    );
+    assert_eq!(client_hello_sni(&hello[..3]), ClientHello::Incomplete);
+    assert_eq!(
+        client_hello_sni(&hello[..hello.len() - 1]),
+        ClientHello::Incomplete
+    );
+    assert_eq!(client_hello_sni(b"GET / HTTP/1.1\r\n"), ClientHello::NotTls);
+}
+
+#[test]
+fn sni_check_scope() {
+    let open = Acl::default();
+    assert!(open.needs_sni("example.com", 443));
+    // Протоколы, где первым говорит сервер, не ждут ClientHello.
+    assert!(!open.needs_sni("mail.example.com", 25));
+    assert!(!open.needs_sni("example.com", 22));
+    // Для IP без правил проверять нечего.
+    assert!(!open.needs_sni("192.0.2.1", 443));
+    assert!(acl(&["deny domain:bad.test"]).needs_sni("192.0.2.1", 443));
+
+    assert!(open.check_sni("example.com", 443, "EXAMPLE.com.").is_ok());
+    let reason = open
+        .check_sni("example.com", 443, "front.example.net")
+        .unwrap_err();
+    assert!(reason.contains("does not match"), "{reason}");
+    assert!(open.check_sni("192.0.2.1", 443, "any.test").is_ok());
+    let deny = acl(&["deny domain:bad.test"]);
+    assert!(deny.check_sni("192.0.2.1", 443, "www.bad.test").is_err());
+}
+
+#[test]
+fn peek_waits_only_for_tls() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let timeout = Duration::from_secs(5);
+
+    // ClientHello, присланный в два приёма.
+    let hello = client_hello(Some("example.com"));
+    let writer = thread::spawn(move || {
+        let mut c = TcpStream::connect(addr).unwrap();
+        c.write_all(&hello[..10]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        c.write_all(&hello[10..]).unwrap();
+        c
+    });
+    let (s, _) = listener.accept().unwrap();
+    assert_eq!(
+        peek_client_hello(&s, timeout).unwrap(),
+        ClientHello::Sni(Some("example.com".to_owned()))
+    );
+    drop(writer.join());
+
+    // Не TLS: ответ по первому байту, без ожидания.
+    let mut c = TcpStream::connect(addr).unwrap();
+    c.write_all(b"EHLO x\r\n").unwrap();
+    let (s, _) = listener.accept().unwrap();
+    let t0 = Instant::now();
+    assert_eq!(peek_client_hello(&s, timeout).unwrap(), ClientHello::NotTls);
+    assert!(t0.elapsed() < Duration::from_secs(1));
+
+    // Клиент молчит: ожидание ограничено таймаутом, таймаут чтения
+    // сокета восстанавливается.
+    let _c = TcpStream::connect(addr).unwrap();
+    let (s, _) = listener.accept().unwrap();
+    s.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+    let t0 = Instant::now();
+    assert_eq!(
+        peek_client_hello(&s, Duration::from_millis(100)).unwrap(),
+        ClientHello::Incomplete
+    );
+    assert!(t0.elapsed() < Duration::from_secs(1));
+    assert_eq!(s.read_timeout().unwrap(), Some(Duration::from_secs(30)));
+}
+
+#[test]
+fn client_filter() {
+    let mut filter = ClientFilter::default();
+    assert!(filter.permits("203.0.113.5".parse().unwrap()));
+    filter.allow.push("192.168.1.0/24".parse().unwrap());
+    filter.allow.push("::1".parse().unwrap());
+    filter.deny.push("192.168.1.13".parse().unwrap());
+    assert!(filter.permits("192.168.1.20".parse().unwrap()));
+    assert!(filter.permits("::1".parse().unwrap()));
+    assert!(!filter.permits("192.168.1.13".parse().unwrap()));
+    assert!(!filter.permits("192.168.2.1".parse().unwrap()));
+}
+
+#[test]
+fn private_destinations() {
+    for ip in [
+        "127.0.0.1",
+        "10.2.3.4",
+        "172.31.0.1",
+        "192.168.0.1",
+        "169.254.169.254",
+        "100.100.100.200",
+        "0.0.0.0",
+        "::1",
+        "::ffff:127.0.0.1",
+        "fd00:ec2::254",
+        "fe80::1",
+        "::",
+        "::127.0.0.1",
+        "::a9fe:a9fe",
+        "64:ff9b::a9fe:a9fe",
+        "64:ff9b:1::a00:1",
+        "2002:7f00:1::1",
+        "224.0.0.1",
+        "ff02::1",
+    ] {
+        assert!(is_private_ip(ip.parse().unwrap()), "{ip}");
+    }
+    for ip in [
+        "8.8.8.8",
+        "172.32.0.1",
+        "100.128.0.1",
+        "2606:4700::1111",
+        "::ffff:8.8.8.8",
+        "64:ff9b:2::1",
+        "2003::1",
+    ] {
+        assert!(!is_private_ip(ip.parse().unwrap()), "{ip}");
+    }
+
+    let mut guard = SsrfGuard::default();
+    assert!(guard.resolve("169.254.169.254", resolve_host).is_err());
+    assert!(guard.resolve("[::1]", resolve_host).is_err());
+    assert!(guard.resolve("localhost", resolve_host).is_err());
+    assert_eq!(
+        guard.resolve("1.1.1.1", resolve_host),
+        Ok(vec!["1.1.1.1".parse().unwrap()])
+    );
+    guard.allow.push("127.0.0.0/8".parse().unwrap());
+    assert_eq!(
+        guard.resolve("127.0.0.1", resolve_host),
+        Ok(vec!["127.0.0.1".parse().unwrap()])
+    );
+    // Проверяются и возвращаются все адреса имени.
+    let both = ["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+    assert_eq!(
+        guard.resolve("dual.test", |_| Ok(both.to_vec())),
+        Ok(both.to_vec())
+    );
+    let mixed = ["192.0.2.1".parse().unwrap(), "10.0.0.1".parse().unwrap()];
+    assert!(guard.resolve("mixed.test", |_| Ok(mixed.to_vec())).is_err());
+}
@@ -0,0 +1,77 @@
+use std::io::Write;
+use std::time::{Duration, UNIX_EPOCH};
+
+use http2socks_proxy::{BodyBuffer, CaptureRecord, HttpRecord, TunnelRecord, base64_encode};
+
+#[test]
+fn body_buffer_keeps_limit_and_counts_all() {
+    let mut body = BodyBuffer::new(4);
+    body.write_all(b"abc").unwrap();
+    body.write_all(b"defgh").unwrap();
+    assert_eq!(body.data, b"abcd");
+    assert_eq!(body.total, 8);
+    assert!(body.truncated());
+
+    let mut small = BodyBuffer::new(16);
+    small.write_all(b"abc").unwrap();
+    assert!(!small.truncated());
+}
+
+#[test]
+fn base64_padding() {
+    assert_eq!(base64_encode(b""), "");
+    assert_eq!(base64_encode(b"f"), "Zg==");
+    assert_eq!(base64_encode(b"fo"), "Zm8=");
+    assert_eq!(base64_encode(b"foo"), "Zm9v");
+    assert_eq!(base64_encode(&[0xff, 0xfe]), "//4=");
+}
+
+#[test]
+fn http_record_json() {
+    let mut request_body = BodyBuffer::new(64);
+    request_body.write_all(b"a=\"1\"\n").unwrap();
+    let mut response_body = BodyBuffer::new(64);
+    response_body.write_all(&[0xff, 0x00]).unwrap();
+    let record = HttpRecord {
+        started: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+        client: Some("127.0.0.1:5000".parse().unwrap()),
+        method: "POST".into(),
+        url: "http://example.com:80/x".into(),
+        request_headers: vec![("Host".into(), "example.com".into())],
+        request_body,
+        status: 200,
+        reason: "OK".into(),
+        response_version: "HTTP/1.1".into(),
+        response_body,
+        ..HttpRecord::default()
+    };
+    let json = CaptureRecord::Http(Box::new(record)).to_json();
+    assert!(json.starts_with(
+        "{\"startedDateTime\":\"2023-11-14T22:13:20.123Z\",\"client\":\"127.0.0.1:5000\""
+    ));
+    assert!(json.contains("\"headers\":[{\"name\":\"Host\",\"value\":\"example.com\"}]"));
+    assert!(
+        json.contains("\"postData\":{\"size\":6,\"text\":\"a=\\\"1\\\"\\n\",\"truncated\":false}")
+    );
+    assert!(json.contains(
+        "\"content\":{\"size\":2,\"text\":\"/wA=\",\"encoding\":\"base64\",\"truncated\":false}"
+    ));
+    assert!(!json.contains('\n'));
+}
+
+#[test]
+fn tunnel_record_json() {
+    let record = TunnelRecord {
+        host: "example.com".into(),
+        port: 443,
+        bytes_sent: 10,
+        bytes_received: 20,
+        duration: Duration::from_millis(1500),
+        ..TunnelRecord::default()
+    };
+    assert_eq!(
+        CaptureRecord::Tunnel(record).to_json(),
+        "{\"startedDateTime\":\"1970-01-01T00:00:00.000Z\",\"client\":null,\"time\":1500,\
+         \"tunnel\":{\"host\":\"example.com\",\"port\":443,\"bytesSent\":10,\"bytesReceived\":20}}"
+    );
+}
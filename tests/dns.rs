@@ -0,0 +1,454 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use http2socks_proxy::{
+    DnsCache, DnsMode, DnsOptions, DnsSource, FamilyPreference, build_dns_query,
+    connect_happy_eyeballs, parse_dns_response, parse_hosts, query_both,
+};
+
+fn ips(list: &[&str]) -> Vec<IpAddr> {
+    list.iter().map(|s| s.parse().unwrap()).collect()
+}
+
+#[test]
+fn family_order_interleaves() {
+    let addrs = ips(&["10.0.0.1", "10.0.0.2", "fd00::1", "fd00::2", "10.0.0.1"]);
+    assert_eq!(
+        FamilyPreference::Any.order(&addrs),
+        ips(&["10.0.0.1", "fd00::1", "10.0.0.2", "fd00::2"])
+    );
+    assert_eq!(
+        FamilyPreference::PreferIpv6.order(&addrs),
+        ips(&["fd00::1", "10.0.0.1", "fd00::2", "10.0.0.2"])
+    );
+    assert_eq!(
+        FamilyPreference::Ipv4Only.order(&addrs),
+        ips(&["10.0.0.1", "10.0.0.2"])
+    );
+    assert_eq!(
+        FamilyPreference::Ipv6Only.order(&ips(&["10.0.0.1"])),
+        Vec::<IpAddr>::new()
+    );
+    assert!("prefer-ipv5".parse::<FamilyPreference>().is_err());
+    assert_eq!(
+        "Local-First".parse::<DnsMode>().unwrap(),
+        DnsMode::LocalFirst
+    );
+}
+
+#[test]
+fn happy_eyeballs_prefers_fastest() {
+    let addrs = ips(&["192.0.2.1", "192.0.2.2"]);
+    let started = Instant::now();
+    let winner = connect_happy_eyeballs(&addrs, Some(Duration::from_millis(50)), |ip| {
+        if ip == "192.0.2.1".parse::<IpAddr>().unwrap() {
+            thread::sleep(Duration::from_secs(2));
+        }
+        Ok(ip)
+    })
+    .unwrap();
+    assert_eq!(winner, addrs[1]);
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn happy_eyeballs_moves_on_after_failure() {
+    let addrs = ips(&["192.0.2.1", "192.0.2.2", "192.0.2.3"]);
+    let tried = Arc::new(Mutex::new(Vec::new()));
+    let log = tried.clone();
+    let res = connect_happy_eyeballs(&addrs, Some(Duration::from_secs(5)), move |ip| {
+        log.lock().unwrap().push(ip);
+        Err::<(), _>(io::Error::other(format!("refused {ip}")))
+    });
+    assert!(res.is_err());
+    // Каждая неудача сразу запускает следующую попытку.
+    assert_eq!(tried.lock().unwrap().len(), 3);
+
+    let sequential = connect_happy_eyeballs(&addrs, None, |ip| {
+        if ip == "192.0.2.3".parse::<IpAddr>().unwrap() {
+            Ok(ip)
+        } else {
+            Err(io::Error::other("refused"))
+        }
+    });
+    assert_eq!(sequential.unwrap(), addrs[2]);
+}
+
+#[test]
+fn connect_to_checked_addresses() {
+    let addrs = ips(&["2001:db8::1", "192.0.2.1", "192.0.2.2"]);
+    let opts = DnsOptions {
+        family: FamilyPreference::Ipv4Only,
+        happy_eyeballs: None,
+        ..DnsOptions::default()
+    };
+    let tried = Arc::new(Mutex::new(Vec::new()));
+    let log = tried.clone();
+    let res = opts.connect_addrs("dual.test", &addrs, move |t| {
+        log.lock().unwrap().push(t.to_owned());
+        if t == "192.0.2.2" {
+            Ok(())
+        } else {
+            Err(io::Error::other("refused"))
+        }
+    });
+    assert!(res.is_ok());
+    // IPv6 отброшен семейством, после отказа первого адреса пробуется второй.
+    assert_eq!(*tried.lock().unwrap(), ["192.0.2.1", "192.0.2.2"]);
+
+    let v6 = ips(&["2001:db8::1"]);
+    let err = opts.connect_addrs("v6.test", &v6, |_| Ok(())).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn dns_modes_choose_socks_target() {
+    let target = |opts: &DnsOptions, host: &str| opts.connect(host, |t| Ok(t.to_owned())).unwrap();
+    let remote = DnsOptions::default();
+    assert_eq!(target(&remote, "localhost"), "localhost");
+    assert_eq!(target(&remote, "[::1]"), "::1");
+
+    let local = DnsOptions {
+        mode: DnsMode::Local,
+        ..DnsOptions::default()
+    };
+    assert!(
+        target(&local, "localhost")
+            .parse::<IpAddr>()
+            .unwrap()
+            .is_loopback()
+    );
+    assert!(
+        local
+            .connect("nonexistent.invalid", |t| Ok(t.to_owned()))
+            .is_err()
+    );
+
+    let local_first = DnsOptions {
+        mode: DnsMode::LocalFirst,
+        ..DnsOptions::default()
+    };
+    assert_eq!(
+        target(&local_first, "nonexistent.invalid"),
+        "nonexistent.invalid"
+    );
+    // Локальные адреса недоступны: имя уходит серверу.
+    let fallback = local_first.connect("localhost", |t| {
+        if t.parse::<IpAddr>().is_ok() {
+            Err(io::Error::other("unreachable"))
+        } else {
+            Ok(t.to_owned())
+        }
+    });
+    assert_eq!(fallback.unwrap(), "localhost");
+    assert!(
+        local
+            .connect("localhost", |t| if t.parse::<IpAddr>().is_ok() {
+                Err(io::Error::other("unreachable"))
+            } else {
+                Ok(t.to_owned())
+            })
+            .is_err()
+    );
+}
+
+// Ответ на запрос `query`: записи (тип, TTL, данные) с именем-указателем на
+// вопрос; при `soa` добавляется SOA в раздел authority.
+fn dns_reply(query: &[u8], answers: &[(u16, u32, &[u8])], rcode: u8, soa: Option<u32>) -> Vec<u8> {
+    let mut out = query[..2].to_vec();
+    out.extend_from_slice(&[0x81, 0x80 | rcode, 0, 1]);
+    out.extend_from_slice(&u16::try_from(answers.len()).unwrap().to_be_bytes());
+    out.extend_from_slice(&[0, u8::from(soa.is_some()), 0, 0]);
+    out.extend_from_slice(&query[12..]);
+    for (rtype, ttl, data) in answers {
+        out.extend_from_slice(&[0xC0, 12]);
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&[0, 1]);
+        out.extend_from_slice(&ttl.to_be_bytes());
+        out.extend_from_slice(&u16::try_from(data.len()).unwrap().to_be_bytes());
+        out.extend_from_slice(data);
+    }
+    if let Some(minimum) = soa {
+        let mut rdata = vec![1, b'a', 0, 1, b'b', 0];
+        for v in [1u32, 2, 3, 4, minimum] {
+            rdata.extend_from_slice(&v.to_be_bytes());
+        }
+        out.extend_from_slice(&[0xC0, 12, 0, 6, 0, 1, 0, 0, 0x0E, 0x10]);
+        out.extend_from_slice(&u16::try_from(rdata.len()).unwrap().to_be_bytes());
+        out.extend_from_slice(&rdata);
+    }
+    out
+}
+
+fn qtype(query: &[u8]) -> u16 {
+    let n = query.len();
+    u16::from_be_bytes([query[n - 4], query[n - 3]])
+}
+
+// DNS-сервер для тестов: `a.test` -> 192.0.2.1 (TTL 300), `zero.test` с
+// TTL 0, остальные имена — NXDOMAIN с SOA minimum 120.
+fn mock_dns_server() -> (SocketAddr, Arc<AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let queries = Arc::new(AtomicUsize::new(0));
+    let counter = queries.clone();
+    thread::spawn(move || {
+        let mut buf = [0u8; 512];
+        while let Ok((n, peer)) = socket.recv_from(&mut buf) {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let q = &buf[..n];
+            let name_is = |name: &[u8]| q[12..].starts_with(name);
+            let reply = if name_is(b"\x01a\x04test\x00") && qtype(q) == 1 {
+                dns_reply(
+                    q,
+                    &[(5, 300, b"\x01b\xC0\x0E"), (1, 300, &[192, 0, 2, 1])],
+                    0,
+                    None,
+                )
+            } else if name_is(b"\x01a\x04test\x00") || name_is(b"\x04zero\x04test\x00") {
+                if qtype(q) == 1 {
+                    dns_reply(q, &[(1, 0, &[192, 0, 2, 2])], 0, None)
+                } else {
+                    dns_reply(q, &[], 0, None)
+                }
+            } else {
+                dns_reply(q, &[], 3, Some(120))
+            };
+            let _ = socket.send_to(&reply, peer);
+        }
+    });
+    (addr, queries)
+}
+
+#[test]
+fn dns_response_parsing() {
+    let query = build_dns_query(0x1234, "a.test", 1).unwrap();
+    assert_eq!(&query[12..], b"\x01a\x04test\x00\x00\x01\x00\x01");
+    let reply = dns_reply(
+        &query,
+        &[
+            (5, 60, b"\x01b\xC0\x0E"),
+            (1, 300, &[192, 0, 2, 1]),
+            (1, 30, &[192, 0, 2, 9]),
+            // Запись другого типа в ответ на запрос A не берётся.
+            (
+                28,
+                10,
+                &[0x20, 1, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            ),
+        ],
+        0,
+        None,
+    );
+    let answer = parse_dns_response(&query, &reply, Duration::from_secs(5)).unwrap();
+    assert_eq!(answer.addrs, ips(&["192.0.2.1", "192.0.2.9"]));
+    assert_eq!(answer.ttl, Duration::from_secs(30));
+    let other_id = build_dns_query(0x4321, "a.test", 1).unwrap();
+    assert!(parse_dns_response(&other_id, &reply, Duration::ZERO).is_err());
+    let other_name = build_dns_query(0x1234, "b.test", 1).unwrap();
+    assert!(parse_dns_response(&other_name, &reply, Duration::ZERO).is_err());
+    let upper = build_dns_query(0x1234, "A.TEST", 1).unwrap();
+    assert!(parse_dns_response(&upper, &reply, Duration::ZERO).is_ok());
+
+    // Усечённый ответ (TC) — ошибка, а не пустой результат.
+    let mut truncated = reply.clone();
+    truncated[2] |= 0x02;
+    assert!(parse_dns_response(&query, &truncated, Duration::ZERO).is_err());
+
+    let nx = dns_reply(&query, &[], 3, Some(120));
+    let answer = parse_dns_response(&query, &nx, Duration::from_secs(5)).unwrap();
+    assert!(answer.addrs.is_empty());
+    assert_eq!(answer.ttl, Duration::from_secs(120));
+
+    let servfail = dns_reply(&query, &[], 2, None);
+    assert!(parse_dns_response(&query, &servfail, Duration::ZERO).is_err());
+}
+
+#[test]
+fn dns_partial_answers_and_families() {
+    // AAAA не отвечает: адрес из A всё равно возвращается.
+    let answer = query_both("a.test", FamilyPreference::Any, Duration::ZERO, |q| {
+        if qtype(q) == 1 {
+            Ok(dns_reply(q, &[(1, 60, &[192, 0, 2, 1])], 0, None))
+        } else {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "no reply"))
+        }
+    })
+    .unwrap();
+    assert_eq!(answer.addrs, ips(&["192.0.2.1"]));
+    assert_eq!(answer.ttl, Duration::from_secs(60));
+
+    // SERVFAIL на AAAA тоже не мешает.
+    let answer = query_both("a.test", FamilyPreference::Any, Duration::ZERO, |q| {
+        Ok(if qtype(q) == 1 {
+            dns_reply(q, &[(1, 60, &[192, 0, 2, 1])], 0, None)
+        } else {
+            dns_reply(q, &[], 2, None)
+        })
+    })
+    .unwrap();
+    assert_eq!(answer.addrs, ips(&["192.0.2.1"]));
+
+    // Оба запроса неудачны — ошибка, а не пустой ответ.
+    let failed = query_both("a.test", FamilyPreference::Any, Duration::ZERO, |_| {
+        Err(io::Error::new(io::ErrorKind::TimedOut, "no reply"))
+    });
+    assert_eq!(failed.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+    // Только IPv4: AAAA не запрашивается.
+    let mut sent = Vec::new();
+    query_both("a.test", FamilyPreference::Ipv4Only, Duration::ZERO, |q| {
+        sent.push(qtype(q));
+        Ok(dns_reply(q, &[(1, 60, &[192, 0, 2, 1])], 0, None))
+    })
+    .unwrap();
+    assert_eq!(sent, [1]);
+}
+
+// Перед настоящим ответом сервер присылает датаграмму с чужим id: клиент
+// должен её пропустить и дождаться своей.
+#[test]
+fn dns_skips_replies_to_other_queries() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = [0u8; 512];
+        while let Ok((n, peer)) = socket.recv_from(&mut buf) {
+            let q = buf[..n].to_vec();
+            let mut stale = q.clone();
+            stale[0] ^= 0xFF;
+            let _ = socket.send_to(
+                &dns_reply(&stale, &[(1, 60, &[192, 0, 2, 7])], 0, None),
+                peer,
+            );
+            let reply = if qtype(&q) == 1 {
+                dns_reply(&q, &[(1, 60, &[192, 0, 2, 1])], 0, None)
+            } else {
+                dns_reply(&q, &[], 0, None)
+            };
+            let _ = socket.send_to(&reply, peer);
+        }
+    });
+    let cache = DnsCache::new(DnsSource::Server(server), 16, Duration::from_secs(30));
+    assert_eq!(cache.lookup("a.test").unwrap(), ips(&["192.0.2.1"]));
+}
+
+#[test]
+fn dns_cache_respects_ttl_and_negative_answers() {
+    let (server, queries) = mock_dns_server();
+    let cache = DnsCache::new(DnsSource::Server(server), 16, Duration::from_secs(30));
+
+    assert_eq!(cache.lookup("a.test").unwrap(), ips(&["192.0.2.1"]));
+    assert_eq!(queries.load(Ordering::SeqCst), 2);
+    assert_eq!(cache.lookup("A.TEST.").unwrap(), ips(&["192.0.2.1"]));
+    assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+    // TTL 0: ответ не кешируется.
+    cache.lookup("zero.test").unwrap();
+    cache.lookup("zero.test").unwrap();
+    assert_eq!(queries.load(Ordering::SeqCst), 6);
+
+    let err = cache.lookup("missing.test").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    cache.lookup("missing.test").unwrap_err();
+    assert_eq!(queries.load(Ordering::SeqCst), 8);
+    assert_eq!(cache.len(), 2);
+}
+
+#[test]
+fn dns_cache_size_and_static_hosts() {
+    let (server, queries) = mock_dns_server();
+    let mut cache = DnsCache::new(DnsSource::Server(server), 1, Duration::from_secs(30));
+    let hosts = parse_hosts("# static\n10.9.9.9 a.test alias.test\n\n::1 a.test\n").unwrap();
+    assert_eq!(hosts.len(), 3);
+    for (name, ip) in hosts {
+        cache.add_host(&name, ip);
+    }
+    assert!(parse_hosts("nope name").is_err());
+
+    assert_eq!(cache.lookup("a.test").unwrap(), ips(&["10.9.9.9", "::1"]));
+    assert_eq!(cache.lookup("Alias.Test").unwrap(), ips(&["10.9.9.9"]));
+    assert_eq!(queries.load(Ordering::SeqCst), 0);
+
+    cache.lookup("x.test").unwrap_err();
+    cache.lookup("y.test").unwrap_err();
+    assert_eq!(cache.len(), 1);
+    // x.test вытеснена y.test.
+    cache.lookup("x.test").unwrap_err();
+    assert_eq!(queries.load(Ordering::SeqCst), 6);
+}
+
+// SOCKS5-сервер, поддерживающий только UDP ASSOCIATE без аутентификации;
+// считает ассоциации. Ретранслятор сообщает адрес 0.0.0.0, клиент должен
+// подставить адрес сервера.
+fn mock_socks_udp() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let associations = Arc::new(AtomicUsize::new(0));
+    let counter = associations.clone();
+    thread::spawn(move || {
+        for s in listener.incoming() {
+            let mut s = s.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            thread::spawn(move || {
+                let mut g = [0u8; 3];
+                s.read_exact(&mut g).unwrap();
+                assert_eq!(g, [0x05, 1, 0x00]);
+                s.write_all(&[0x05, 0x00]).unwrap();
+                let mut req = [0u8; 10];
+                s.read_exact(&mut req).unwrap();
+                assert_eq!(req[..4], [0x05, 0x03, 0x00, 0x01]);
+
+                let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+                let port = relay.local_addr().unwrap().port().to_be_bytes();
+                s.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, port[0], port[1]])
+                    .unwrap();
+                thread::spawn(move || {
+                    let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+                    let mut buf = [0u8; 1024];
+                    while let Ok((n, client)) = relay.recv_from(&mut buf) {
+                        assert_eq!(buf[..4], [0, 0, 0, 0x01]);
+                        let target = SocketAddr::from((
+                            [buf[4], buf[5], buf[6], buf[7]],
+                            u16::from_be_bytes([buf[8], buf[9]]),
+                        ));
+                        upstream.send_to(&buf[10..n], target).unwrap();
+                        let mut reply = buf[..10].to_vec();
+                        let mut resp = [0u8; 1024];
+                        let (m, _) = upstream.recv_from(&mut resp).unwrap();
+                        reply.extend_from_slice(&resp[..m]);
+                        relay.send_to(&reply, client).unwrap();
+                    }
+                });
+                // Ассоциация живёт, пока открыто управляющее соединение.
+                let _ = s.read(&mut [0u8; 1]);
+            });
+        }
+    });
+    (addr.to_string(), associations)
+}
+
+#[test]
+fn dns_over_socks_udp_associate() {
+    let (server, queries) = mock_dns_server();
+    let (socks_addr, associations) = mock_socks_udp();
+    let source = DnsSource::Socks {
+        socks_addr,
+        user: None,
+        pass: None,
+        server,
+    };
+    let cache = DnsCache::new(source, 16, Duration::from_secs(30));
+    assert_eq!(cache.lookup("a.test").unwrap(), ips(&["192.0.2.1"]));
+    assert_eq!(queries.load(Ordering::SeqCst), 2);
+    assert_eq!(cache.lookup("a.test").unwrap(), ips(&["192.0.2.1"]));
+    assert_eq!(queries.load(Ordering::SeqCst), 2);
+    // Следующий поиск идёт через ту же ассоциацию.
+    assert_eq!(cache.lookup("zero.test").unwrap(), ips(&["192.0.2.2"]));
+    assert_eq!(queries.load(Ordering::SeqCst), 4);
+    assert_eq!(associations.load(Ordering::SeqCst), 1);
+}
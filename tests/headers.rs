@@ -0,0 +1,149 @@
+use std::net::IpAddr;
+
+use http2socks_proxy::{
+    ClientIpHeader, Direction, ForwardingOptions, HeaderMode, HeaderRule, HeaderRules, HostPattern,
+    Scheme, apply_forwarding_headers,
+};
+
+fn h(k: &str, v: &str) -> (String, String) {
+    (k.to_owned(), v.to_owned())
+}
+
+#[test]
+fn forwarding_add_appends_to_existing() {
+    let opts = ForwardingOptions {
+        via: HeaderMode::Add,
+        client_ip: HeaderMode::Add,
+        client_ip_header: ClientIpHeader::Forwarded,
+        listener: Scheme::Http,
+    };
+    let mut headers = vec![
+        h("Host", "example.com"),
+        h("Via", "1.0 upstream"),
+        h("Forwarded", "for=192.0.2.1"),
+    ];
+    let ip: IpAddr = "2001:db8::1".parse().unwrap();
+    apply_forwarding_headers(&mut headers, &opts, Some(ip));
+    assert_eq!(
+        headers,
+        vec![
+            h("Host", "example.com"),
+            h("Via", "1.0 upstream, 1.1 http2socks-proxy"),
+            h(
+                "Forwarded",
+                "for=192.0.2.1, for=\"[2001:db8::1]\";proto=http"
+            ),
+        ]
+    );
+
+    // За TLS-слушателем клиент пришёл по HTTPS.
+    let tls = ForwardingOptions {
+        listener: Scheme::Https,
+        ..opts
+    };
+    let mut headers = vec![h("Host", "example.com")];
+    apply_forwarding_headers(&mut headers, &tls, "192.0.2.7".parse().ok());
+    assert_eq!(
+        headers.last(),
+        Some(&h("Forwarded", "for=192.0.2.7;proto=https"))
+    );
+}
+
+#[test]
+fn forwarding_x_forwarded_for() {
+    let opts = ForwardingOptions {
+        via: HeaderMode::Leave,
+        client_ip: HeaderMode::Add,
+        client_ip_header: ClientIpHeader::XForwardedFor,
+        listener: Scheme::Http,
+    };
+    let mut headers = vec![h("Host", "example.com")];
+    apply_forwarding_headers(&mut headers, &opts, "10.1.2.3".parse().ok());
+    assert_eq!(
+        headers,
+        vec![h("Host", "example.com"), h("X-Forwarded-For", "10.1.2.3")]
+    );
+}
+
+#[test]
+fn forwarding_privacy_strips() {
+    let mut headers = vec![
+        h("Host", "example.com"),
+        h("Via", "1.1 other"),
+        h("X-Forwarded-For", "10.0.0.1"),
+        h("forwarded", "for=10.0.0.1"),
+        h("X-Real-IP", "10.0.0.1"),
+        h("Accept", "*/*"),
+    ];
+    apply_forwarding_headers(
+        &mut headers,
+        &ForwardingOptions::privacy(),
+        "10.1.2.3".parse().ok(),
+    );
+    assert_eq!(headers, vec![h("Host", "example.com"), h("Accept", "*/*")]);
+}
+
+#[test]
+fn host_patterns() {
+    let any: HostPattern = "*".parse().unwrap();
+    let subs: HostPattern = "*.Tracker.net".parse().unwrap();
+    let exact: HostPattern = "api.internal".parse().unwrap();
+    assert!(any.matches("anything.example"));
+    assert!(subs.matches("ads.tracker.net"));
+    assert!(subs.matches("a.b.TRACKER.net."));
+    assert!(!subs.matches("tracker.net"));
+    assert!(!subs.matches("eviltracker.net"));
+    assert!(exact.matches("API.internal"));
+    assert!(!exact.matches("x.api.internal"));
+}
+
+#[test]
+fn rules_apply_by_host_and_direction() {
+    let rules = HeaderRules::parse(
+        "# comment\n\
+         request example.com set User-Agent: custom/1.0\n\
+         request *.tracker.net remove Cookie\n\
+         request api.internal add Authorization: Bearer t0k3n\n\
+         response * set Cache-Control: no-store\n",
+    )
+    .expect("parse rules");
+
+    let mut headers = vec![
+        h("User-Agent", "curl"),
+        h("Cookie", "a=1"),
+        h("user-agent", "dup"),
+    ];
+    rules.apply(Direction::Request, "example.com", &mut headers);
+    assert_eq!(
+        headers,
+        vec![h("User-Agent", "custom/1.0"), h("Cookie", "a=1")]
+    );
+
+    let mut headers = vec![h("Cookie", "a=1"), h("Accept", "*/*")];
+    rules.apply(Direction::Request, "pixel.tracker.net", &mut headers);
+    assert_eq!(headers, vec![h("Accept", "*/*")]);
+
+    let mut headers = vec![];
+    rules.apply(Direction::Request, "api.internal", &mut headers);
+    assert_eq!(headers, vec![h("Authorization", "Bearer t0k3n")]);
+
+    let mut headers = vec![h("Content-Type", "text/plain")];
+    rules.apply(Direction::Response, "api.internal", &mut headers);
+    assert_eq!(
+        headers,
+        vec![
+            h("Content-Type", "text/plain"),
+            h("Cache-Control", "no-store")
+        ]
+    );
+}
+
+#[test]
+fn rule_parse_errors() {
+    assert!("inbound * set X: 1".parse::<HeaderRule>().is_err());
+    assert!("request * set X".parse::<HeaderRule>().is_err());
+    assert!("request * remove X: 1".parse::<HeaderRule>().is_err());
+    assert!("request * drop X".parse::<HeaderRule>().is_err());
+    let err = HeaderRules::parse("request * add X: 1\nbogus\n").unwrap_err();
+    assert!(err.to_string().starts_with("line 2:"));
+}
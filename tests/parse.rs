@@ -1,4 +1,8 @@
-use http2socks_proxy::{RequestTarget, parse_request_head};
+use http2socks_proxy::{
+    RequestTarget, Scheme, body_after_head, parse_request_head, parse_response_head,
+    read_final_response_head, serialize_request_head, strip_hop_by_hop,
+    write_modified_request_head, write_modified_response_head,
+};
 
 #[test]
 fn parse_connect() {
@@ -20,12 +24,14 @@ fn parse_absolute_form() {
     match parsed {
         RequestTarget::Http {
             method,
+            scheme,
             host,
             port,
             path,
             ..
         } => {
             assert_eq!(method, "GET");
+            assert_eq!(scheme, Scheme::Http);
             assert_eq!(host, "example.com");
             assert_eq!(port, 8080);
             assert_eq!(path, "/path?q=1");
@@ -33,3 +39,257 @@ fn parse_absolute_form() {
         _ => panic!("expected HTTP"),
     }
 }
+
+#[test]
+fn parse_rejects_unknown_scheme() {
+    for req in [
+        &b"GET ftp://example.com/x HTTP/1.1\r\n\r\n"[..],
+        b"GET ws://example.com/ HTTP/1.1\r\n\r\n",
+    ] {
+        let err = parse_request_head(req).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+    // "://" в строке запроса origin-формы схемой не считается.
+    let req = b"GET /go?to=http://example.com/ HTTP/1.1\r\nHost: a\r\n\r\n";
+    match parse_request_head(req).expect("origin form") {
+        RequestTarget::Http { scheme, path, .. } => {
+            assert_eq!(scheme, Scheme::Http);
+            assert_eq!(path, "/go?to=http://example.com/");
+        }
+        _ => panic!("expected HTTP"),
+    }
+}
+
+#[test]
+fn parse_default_port_by_scheme() {
+    let cases: [(&[u8], Scheme, &str, u16); 4] = [
+        (
+            b"GET https://example.com/x HTTP/1.1\r\n\r\n",
+            Scheme::Https,
+            "example.com",
+            443,
+        ),
+        (
+            b"GET HTTPS://example.com HTTP/1.1\r\n\r\n",
+            Scheme::Https,
+            "example.com",
+            443,
+        ),
+        (
+            b"GET http://example.com/ HTTP/1.1\r\n\r\n",
+            Scheme::Http,
+            "example.com",
+            80,
+        ),
+        (
+            b"GET https://example.com:8443/ HTTP/1.1\r\n\r\n",
+            Scheme::Https,
+            "example.com",
+            8443,
+        ),
+    ];
+    for (raw, want_scheme, want_host, want_port) in cases {
+        match parse_request_head(raw).expect("parse") {
+            RequestTarget::Http {
+                scheme, host, port, ..
+            } => {
+                assert_eq!(scheme, want_scheme);
+                assert_eq!(host, want_host);
+                assert_eq!(port, want_port);
+            }
+            RequestTarget::Connect { .. } => panic!("expected HTTP"),
+        }
+    }
+}
+
+#[test]
+fn modified_head_uses_crlf() {
+    let headers = vec![
+        ("Host".to_owned(), "example.com".to_owned()),
+        ("Proxy-Connection".to_owned(), "keep-alive".to_owned()),
+        ("X-Test".to_owned(), "1".to_owned()),
+    ];
+    let mut out = Vec::new();
+    write_modified_request_head(&mut out, "GET", "/", &headers).expect("write head");
+    let text = String::from_utf8(out).unwrap();
+    assert_eq!(
+        text,
+        "GET / HTTP/1.1\r\nHost: example.com\r\nX-Test: 1\r\nConnection: close\r\n\r\n"
+    );
+    assert!(!text.replace("\r\n", "").contains('\n'));
+}
+
+fn h(k: &str, v: &str) -> (String, String) {
+    (k.to_owned(), v.to_owned())
+}
+
+#[test]
+fn strips_hop_by_hop_request_headers() {
+    let headers = vec![
+        h("Host", "example.com"),
+        h("Connection", "keep-alive, X-Trace"),
+        h("Keep-Alive", "timeout=5"),
+        h("TE", "trailers"),
+        h("Trailer", "Expires"),
+        h("Upgrade", "websocket"),
+        h("Proxy-Authorization", "Basic Zm9v"),
+        h("Proxy-Authenticate", "Basic"),
+        h("X-Trace", "abc"),
+        h("Transfer-Encoding", "chunked"),
+        h("Accept", "*/*"),
+    ];
+    let mut out = Vec::new();
+    write_modified_request_head(&mut out, "POST", "/upload", &headers).expect("write head");
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "POST /upload HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\
+         Accept: */*\r\nConnection: close\r\n\r\n"
+    );
+}
+
+#[test]
+fn keeps_upgrade_when_tunnelling() {
+    let headers = vec![
+        h("Host", "example.com"),
+        h("Connection", "Upgrade"),
+        h("Upgrade", "websocket"),
+    ];
+    assert_eq!(
+        strip_hop_by_hop(&headers, true),
+        vec![h("Host", "example.com"), h("Upgrade", "websocket")]
+    );
+    let mut out = Vec::new();
+    write_modified_request_head(&mut out, "GET", "/ws", &headers).expect("write head");
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "GET /ws HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+         Connection: upgrade\r\n\r\n"
+    );
+}
+
+#[test]
+fn response_hop_by_hop() {
+    let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: keep-alive, X-Internal\r\n\
+                Keep-Alive: timeout=5\r\nX-Internal: 1\r\nProxy-Authenticate: Basic\r\n\r\n";
+    let resp = parse_response_head(raw).expect("parse response");
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.reason, "OK");
+    let mut out = Vec::new();
+    write_modified_response_head(&mut out, &resp, false).expect("write head");
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n"
+    );
+
+    let switching = parse_response_head(
+        b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
+    )
+    .expect("parse 101");
+    let mut out = Vec::new();
+    write_modified_response_head(&mut out, &switching, true).expect("write head");
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: upgrade\r\n\r\n"
+    );
+}
+
+#[test]
+fn interim_responses_before_final() {
+    // 100 Continue и окончательный ответ пришли одним куском.
+    let raw: &[u8] = b"HTTP/1.1 100 Continue\r\nConnection: keep-alive\r\n\r\n\
+                       HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: foo\r\nFoo: x\r\n\r\nok";
+    let mut upstream = raw;
+    let mut client = Vec::new();
+    let mut buf = Vec::new();
+    let resp = read_final_response_head(&mut upstream, &mut client, &mut buf, |resp| {
+        resp.headers.push(("X-Rule".to_owned(), "1".to_owned()));
+    })
+    .expect("read")
+    .expect("final head");
+    assert_eq!(resp.status, 200);
+    assert_eq!(body_after_head(&buf), b"ok");
+    write_modified_response_head(&mut client, &resp, false).expect("write head");
+    assert_eq!(
+        String::from_utf8(client).unwrap(),
+        "HTTP/1.1 100 Continue\r\nX-Rule: 1\r\n\r\n\
+         HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-Rule: 1\r\nConnection: close\r\n\r\n"
+    );
+
+    // Оборванный заголовок остаётся в буфере и уходит клиенту как есть.
+    let mut upstream: &[u8] = b"HTTP/1.1 103 Early Hints\r\n\r\nHTTP/1.1 2";
+    let mut client = Vec::new();
+    let mut buf = Vec::new();
+    let resp = read_final_response_head(&mut upstream, &mut client, &mut buf, |_| {}).unwrap();
+    assert!(resp.is_none());
+    assert_eq!(buf, b"HTTP/1.1 2");
+}
+
+// Простой детерминированный генератор (xorshift64*), чтобы не тянуть
+// зависимости ради property-тестов.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn string(&mut self, alphabet: &[u8], min: usize, max: usize) -> String {
+        let len = min + self.below(max - min + 1);
+        (0..len)
+            .map(|_| alphabet[self.below(alphabet.len())] as char)
+            .collect()
+    }
+}
+
+const TOKEN: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_";
+const HOST: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789-.";
+const PATH: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789-._~/?&=%";
+const VALUE: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789 ,;=/:\"()";
+
+fn random_target(rng: &mut Rng) -> RequestTarget {
+    let host = rng.string(HOST, 1, 40);
+    let port = u16::try_from(rng.below(usize::from(u16::MAX) + 1)).unwrap();
+    if rng.below(4) == 0 {
+        return RequestTarget::Connect { host, port };
+    }
+    let methods = ["GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "PATCH"];
+    let method = methods[rng.below(methods.len())].to_owned();
+    let scheme = if rng.below(2) == 0 {
+        Scheme::Http
+    } else {
+        Scheme::Https
+    };
+    let path = format!("/{}", rng.string(PATH, 0, 60));
+    let headers = (0..rng.below(12))
+        .map(|_| {
+            let value = rng.string(VALUE, 0, 50).trim().to_owned();
+            (rng.string(TOKEN, 1, 20), value)
+        })
+        .collect();
+    RequestTarget::Http {
+        method,
+        scheme,
+        host,
+        port,
+        path,
+        headers,
+    }
+}
+
+#[test]
+fn serialize_parse_roundtrip() {
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    for _ in 0..2000 {
+        let target = random_target(&mut rng);
+        let bytes = serialize_request_head(&target);
+        let parsed = parse_request_head(&bytes).expect("parse serialized head");
+        assert_eq!(parsed, target, "{}", String::from_utf8_lossy(&bytes));
+    }
+}
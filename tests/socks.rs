@@ -1,107 +1,483 @@
-use std::io::{Read, Write};
-use std::net::TcpListener;
+use std::io::{Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
 use std::thread;
+use std::time::Duration;
 
-use http2socks_proxy::socks5_connect;
+use http2socks_proxy::{
+    CustomAuth, NoAuth, ReadWrite, Socks5Bind, Socks5Datagram, TargetAddr, Upstream,
+    UpstreamProtocol, UserPassAuth, connect_chain, decode_udp_datagram, encode_udp_datagram,
+    http_connect, socks4_connect, socks5_accept, socks5_connect, socks5_connect_over,
+    socks5_connect_with, socks5_read_greeting, socks5_read_request, socks5_reply_code,
+    socks5_select_method, socks5_write_reply,
+};
 
-fn spawn_mock_socks(expect_auth: bool, expect_host: &str, expect_port: u16) -> String {
+// `method` — метод аутентификации, который выберет сервер.
+fn spawn_mock_socks(method: u8, cmd: u8, expect_host: &str, expect_port: u16) -> String {
     let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
     let addr = listener.local_addr().unwrap();
-    let host = expect_host.to_string();
+    let expect = TargetAddr::new(expect_host, expect_port);
     thread::spawn(move || {
         let (mut s, _) = listener.accept().expect("accept");
-        // Читаем приветствие клиента (greeting).
-        let mut g = [0u8; 3];
-        s.read_exact(&mut g[..2]).unwrap();
-        assert_eq!(g[0], 0x05);
-        let n = g[1] as usize;
-        let mut methods = vec![0u8; n];
-        s.read_exact(&mut methods).unwrap();
-        if expect_auth {
-            // Сервер выбирает метод аутентификации: username/password.
-            s.write_all(&[0x05, 0x02]).unwrap();
-            // Затем читаем сабпротокол аутентификации (RFC1929).
-            let mut ver = [0u8; 1];
-            s.read_exact(&mut ver).unwrap();
-            assert_eq!(ver[0], 0x01);
-            let mut ulen = [0u8; 1];
-            s.read_exact(&mut ulen).unwrap();
-            let mut ubuf = vec![0u8; ulen[0] as usize];
-            s.read_exact(&mut ubuf).unwrap();
-            let mut plen = [0u8; 1];
-            s.read_exact(&mut plen).unwrap();
-            let mut pbuf = vec![0u8; plen[0] as usize];
-            s.read_exact(&mut pbuf).unwrap();
-            // Принять аутентификацию (успех).
-            s.write_all(&[0x01, 0x00]).unwrap();
-        } else {
-            // Выбор метода без аутентификации.
-            s.write_all(&[0x05, 0x00]).unwrap();
-        }
-
-        // Читаем запрос CONNECT от клиента.
-        let mut hdr = [0u8; 4];
-        s.read_exact(&mut hdr).unwrap();
-        assert_eq!(
-            hdr,
-            [
-                0x05,
-                0x01,
-                0x00,
-                if host.parse::<std::net::Ipv4Addr>().is_ok() {
-                    0x01
-                } else if host.parse::<std::net::Ipv6Addr>().is_ok() {
-                    0x04
-                } else {
-                    0x03
-                }
-            ]
-        );
-
-        match hdr[3] {
-            0x01 => {
-                let mut addr = [0u8; 4];
-                s.read_exact(&mut addr).unwrap();
-            }
-            0x04 => {
-                let mut addr = [0u8; 16];
-                s.read_exact(&mut addr).unwrap();
+        let req = match method {
+            0x00 => socks5_accept(&mut s, None).unwrap(),
+            0x02 => socks5_accept(&mut s, Some(("u", "p"))).unwrap(),
+            _ => {
+                // Частный метод: клиент шлёт токен, сервер подтверждает.
+                let methods = socks5_read_greeting(&mut s).unwrap();
+                assert!(methods.contains(&method), "{methods:?}");
+                socks5_select_method(&mut s, method).unwrap();
+                let mut token = [0u8; 5];
+                s.read_exact(&mut token).unwrap();
+                assert_eq!(&token, b"token");
+                s.write_all(&[0x00]).unwrap();
+                socks5_read_request(&mut s).unwrap()
             }
-            0x03 => {
-                let mut l = [0u8; 1];
-                s.read_exact(&mut l).unwrap();
-                let mut name = vec![0u8; l[0] as usize];
-                s.read_exact(&mut name).unwrap();
-                assert_eq!(String::from_utf8(name).unwrap(), host);
-            }
-            _ => unreachable!(),
-        }
-        let mut portb = [0u8; 2];
-        s.read_exact(&mut portb).unwrap();
-        let port = u16::from_be_bytes(portb);
-        assert_eq!(port, expect_port);
-
-        // Отвечаем успехом, якобы привязаны к 0.0.0.0:0.
-        s.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
-            .unwrap();
+        };
+        assert_eq!(req.command, cmd);
+        assert_eq!(req.target, expect);
 
-        // Ненадолго держим соединение открытым.
-        let _ = s.flush();
+        if cmd == 0x02 {
+            // Первый ответ BIND: сервер слушает на 0.0.0.0:2121.
+            let listening = TargetAddr::new("0.0.0.0", 2121);
+            socks5_write_reply(&mut s, 0x00, &listening).unwrap();
+            // Второй ответ: подключился 203.0.113.7:20, дальше его данные.
+            let peer = TargetAddr::new("203.0.113.7", 20);
+            socks5_write_reply(&mut s, 0x00, &peer).unwrap();
+            s.write_all(b"hello").unwrap();
+        } else {
+            // Отвечаем успехом, якобы привязаны к 0.0.0.0:0.
+            socks5_write_reply(&mut s, 0x00, &TargetAddr::new("0.0.0.0", 0)).unwrap();
+        }
     });
     addr.to_string()
 }
 
 #[test]
 fn socks_no_auth_domain() {
-    let addr = spawn_mock_socks(false, "example.com", 80);
+    let addr = spawn_mock_socks(0x00, 0x01, "example.com", 80);
     let stream = socks5_connect(&addr, "example.com", 80, None, None).expect("connect via socks");
     let _ = stream.shutdown(std::net::Shutdown::Both);
 }
 
 #[test]
 fn socks_with_auth_ipv4() {
-    let addr = spawn_mock_socks(true, "127.0.0.1", 8080);
+    let addr = spawn_mock_socks(0x02, 0x01, "127.0.0.1", 8080);
     let stream =
         socks5_connect(&addr, "127.0.0.1", 8080, Some("u"), Some("p")).expect("connect via socks");
     let _ = stream.shutdown(std::net::Shutdown::Both);
 }
+
+#[test]
+fn socks_custom_auth_method() {
+    let addr = spawn_mock_socks(0x81, 0x01, "example.com", 443);
+    let custom = CustomAuth::new(0x81, |s: &mut dyn ReadWrite| {
+        s.write_all(b"token")?;
+        let mut status = [0u8; 1];
+        s.read_exact(&mut status)?;
+        if status[0] == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::other("token rejected"))
+        }
+    })
+    .unwrap();
+    let tcp = TcpStream::connect(&addr).unwrap();
+    let user_pass = UserPassAuth {
+        user: "u".to_owned(),
+        pass: "p".to_owned(),
+    };
+    socks5_connect_with(tcp, "example.com", 443, &[&NoAuth, &user_pass, &custom])
+        .expect("connect with private method");
+
+    assert!(CustomAuth::new(0x02, |_: &mut dyn ReadWrite| Ok(())).is_err());
+    assert!(CustomAuth::new(0xFF, |_: &mut dyn ReadWrite| Ok(())).is_err());
+
+    // Сервер выбрал метод, которого клиент не предлагал.
+    let unoffered = socks5_connect_with(
+        ScriptedStream::new(&[0x05, 0x81]),
+        "example.com",
+        443,
+        &[&NoAuth],
+    );
+    let err = unoffered.err().expect("unoffered method");
+    assert!(err.to_string().contains("0x81"), "{err}");
+    let none = socks5_connect_with(
+        ScriptedStream::new(&[0x05, 0xFF]),
+        "example.com",
+        443,
+        &[&NoAuth],
+    );
+    assert_eq!(
+        none.err().map(|e| e.kind()),
+        Some(std::io::ErrorKind::PermissionDenied)
+    );
+}
+
+// Поток в памяти: ответы сервера заданы заранее, запросы клиента
+// накапливаются для проверки.
+struct ScriptedStream {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl ScriptedStream {
+    fn new(replies: &[u8]) -> Self {
+        Self {
+            input: Cursor::new(replies.to_vec()),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Read for ScriptedStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for ScriptedStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn socks_handshake_in_memory() {
+    let mut replies = vec![0x05, 0x02, 0x01, 0x00];
+    replies.extend_from_slice(&[0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x04, 0x38]);
+    replies.extend_from_slice(b"payload");
+    let mut stream = socks5_connect_over(
+        ScriptedStream::new(&replies),
+        "example.com",
+        443,
+        Some("u"),
+        Some("pw"),
+    )
+    .expect("handshake");
+
+    let mut expect = vec![0x05, 0x02, 0x00, 0x02];
+    expect.extend_from_slice(&[0x01, 1, b'u', 2, b'p', b'w']);
+    expect.extend_from_slice(&[0x05, 0x01, 0x00, 0x03, 11]);
+    expect.extend_from_slice(b"example.com");
+    expect.extend_from_slice(&443u16.to_be_bytes());
+    assert_eq!(stream.output, expect);
+    // Данные после ответа остаются в потоке.
+    let mut rest = String::new();
+    stream.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "payload");
+
+    let rejected = socks5_connect_over(
+        ScriptedStream::new(&[0x05, 0x02, 0x01, 0x01]),
+        "example.com",
+        443,
+        Some("u"),
+        Some("bad"),
+    );
+    assert_eq!(
+        rejected.err().map(|e| e.kind()),
+        Some(std::io::ErrorKind::PermissionDenied)
+    );
+
+    let refused = socks5_connect_over(
+        ScriptedStream::new(&[0x05, 0x00, 0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0]),
+        "10.0.0.1",
+        22,
+        None,
+        None,
+    );
+    let err = refused.err().expect("connection refused");
+    assert!(err.to_string().contains("0x05"), "{err}");
+}
+
+#[test]
+fn socks_server_accept_in_memory() {
+    // Приветствие с методами 0x00 и 0x02, RFC 1929, CONNECT [::1]:8443.
+    let mut script = vec![0x05, 0x02, 0x00, 0x02];
+    script.extend_from_slice(&[0x01, 0x01, b'u', 0x01, b'p']);
+    script.extend_from_slice(&[0x05, 0x01, 0x00, 0x04]);
+    script.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+    script.extend_from_slice(&8443u16.to_be_bytes());
+    let mut stream = ScriptedStream::new(&script);
+    let req = socks5_accept(&mut stream, Some(("u", "p"))).expect("accept");
+    assert_eq!(req.command, 0x01);
+    assert_eq!(req.target, TargetAddr::new("::1", 8443));
+    socks5_write_reply(&mut stream, 0x00, &TargetAddr::new("example.com", 1)).unwrap();
+    let mut expect = vec![0x05, 0x02, 0x01, 0x00, 0x05, 0x00, 0x00, 0x03, 11];
+    expect.extend_from_slice(b"example.com");
+    expect.extend_from_slice(&[0x00, 0x01]);
+    assert_eq!(stream.output, expect);
+
+    // Неверный пароль: статус 0x01 и отказ.
+    let mut stream = ScriptedStream::new(&[0x05, 0x01, 0x02, 0x01, 0x01, b'u', 0x01, b'x']);
+    let err = socks5_accept(&mut stream, Some(("u", "p"))).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(stream.output, [0x05, 0x02, 0x01, 0x01]);
+
+    // Клиент без пароля к серверу, который его требует: 0xFF.
+    let mut stream = ScriptedStream::new(&[0x05, 0x01, 0x00]);
+    let err = socks5_accept(&mut stream, Some(("u", "p"))).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(stream.output, [0x05, 0xFF]);
+
+    let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+    assert_eq!(socks5_reply_code(&refused), 0x05);
+    assert_eq!(socks5_reply_code(&std::io::Error::other("x")), 0x01);
+}
+
+#[test]
+fn socks_bind_two_replies() {
+    let addr = spawn_mock_socks(0x02, 0x02, "ftp.example.com", 21);
+    let bind = Socks5Bind::open(&addr, "ftp.example.com", 21, Some("u"), Some("p"))
+        .expect("bind via socks");
+    assert_eq!(
+        bind.bound_addr(),
+        &TargetAddr::new("127.0.0.1", 2121),
+        "unspecified bound address is replaced by the server address"
+    );
+    let (mut stream, peer) = bind.accept(Some(Duration::from_secs(5))).expect("accept");
+    assert_eq!(peer, TargetAddr::new("203.0.113.7", 20));
+    let mut data = [0u8; 5];
+    stream.read_exact(&mut data).unwrap();
+    assert_eq!(&data, b"hello");
+}
+
+// SOCKS4-сервер: проверяет запрос целиком и отвечает кодом `reply`.
+fn spawn_mock_socks4(expect: Vec<u8>, reply: u8) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut s, _) = listener.accept().expect("accept");
+        let mut req = vec![0u8; expect.len()];
+        s.read_exact(&mut req).unwrap();
+        assert_eq!(req, expect);
+        s.write_all(&[0x00, reply, 0, 0, 0, 0, 0, 0]).unwrap();
+    });
+    addr.to_string()
+}
+
+#[test]
+fn socks4_ipv4_with_user_id() {
+    let mut expect = vec![0x04, 0x01, 0x1f, 0x90, 10, 0, 0, 5];
+    expect.extend_from_slice(b"alice\0");
+    let addr = spawn_mock_socks4(expect, 0x5a);
+    socks4_connect(&addr, "10.0.0.5", 8080, Some("alice"), false).expect("connect via socks4");
+
+    let addr = spawn_mock_socks4(vec![0x04, 0x01, 0, 80, 10, 0, 0, 5, 0], 0x5b);
+    let err = socks4_connect(&addr, "10.0.0.5", 80, None, false).unwrap_err();
+    assert!(err.to_string().contains("0x5b"), "{err}");
+
+    assert!(socks4_connect("127.0.0.1:1", "example.com", 80, None, false).is_err());
+    assert!(socks4_connect("127.0.0.1:1", "::1", 80, None, true).is_err());
+}
+
+#[test]
+fn socks4a_domain() {
+    let mut expect = vec![0x04, 0x01, 0x01, 0xbb, 0, 0, 0, 1];
+    expect.extend_from_slice(b"\0example.com\0");
+    let addr = spawn_mock_socks4(expect, 0x5a);
+    let upstream: Upstream = format!("socks4a://{addr}").parse().unwrap();
+    upstream
+        .connect("example.com", 443)
+        .expect("connect via socks4a");
+}
+
+// Звенья цепочки в одном соединении: после ответа первого сервера
+// рукопожатие следующего идёт по тому же потоку.
+fn spawn_mock_socks_chain(targets: &'static [(&'static str, u16)]) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut s, _) = listener.accept().expect("accept");
+        for (host, port) in targets {
+            let req = socks5_accept(&mut s, None).unwrap();
+            assert_eq!(req.command, 0x01);
+            assert_eq!(req.target, TargetAddr::Domain((*host).to_owned(), *port));
+            socks5_write_reply(&mut s, 0x00, &TargetAddr::new("0.0.0.0", 0)).unwrap();
+        }
+        s.write_all(b"done").unwrap();
+    });
+    addr.to_string()
+}
+
+#[test]
+fn socks_chain() {
+    let addr = spawn_mock_socks_chain(&[
+        ("hop2.test", 1081),
+        ("hop3.test", 1082),
+        ("example.com", 443),
+    ]);
+    let chain: Vec<Upstream> = [
+        format!("socks5h://{addr}"),
+        "socks5h://hop2.test:1081".to_owned(),
+        "socks5h://hop3.test:1082".to_owned(),
+    ]
+    .iter()
+    .map(|url| url.parse().unwrap())
+    .collect();
+    let mut stream = connect_chain(&chain, "example.com", 443).expect("connect via chain");
+    let mut done = [0u8; 4];
+    stream.read_exact(&mut done).unwrap();
+    assert_eq!(&done, b"done");
+
+    assert!(connect_chain(&[], "example.com", 443).is_err());
+    let mixed: Vec<Upstream> = ["socks5h://127.0.0.1:1", "http://proxy.test:3128"]
+        .iter()
+        .map(|url| url.parse().unwrap())
+        .collect();
+    let err = connect_chain(&mixed, "example.com", 443).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{err}");
+}
+
+// Родительский HTTP-прокси: проверяет запрос CONNECT и отвечает `status`;
+// сразу за ответом шлёт данные туннеля.
+fn spawn_mock_http_proxy(expect: &'static str, status: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut s, _) = listener.accept().expect("accept");
+        let mut req = vec![0u8; expect.len()];
+        s.read_exact(&mut req).unwrap();
+        assert_eq!(String::from_utf8(req).unwrap(), expect);
+        s.write_all(format!("HTTP/1.1 {status}\r\nX-Test: 1\r\n\r\n220 ready\r\n").as_bytes())
+            .unwrap();
+    });
+    addr.to_string()
+}
+
+#[test]
+fn http_connect_upstream() {
+    let addr = spawn_mock_http_proxy(
+        "CONNECT mail.example.com:25 HTTP/1.1\r\nHost: mail.example.com:25\r\n\
+         Proxy-Authorization: Basic dTpw\r\n\r\n",
+        "200 Connection established",
+    );
+    let upstream: Upstream = format!("http://u:p@{addr}").parse().unwrap();
+    assert!(upstream.protocol.remote_dns());
+    let mut stream = upstream
+        .connect("mail.example.com", 25)
+        .expect("connect via http");
+    let mut banner = [0u8; 11];
+    stream.read_exact(&mut banner).unwrap();
+    assert_eq!(&banner, b"220 ready\r\n");
+
+    let addr = spawn_mock_http_proxy(
+        "CONNECT [::1]:443 HTTP/1.1\r\nHost: [::1]:443\r\n\r\n",
+        "407 Proxy Authentication Required",
+    );
+    let err = http_connect(&addr, "::1", 443, None, None).unwrap_err();
+    assert!(err.to_string().contains("407"), "{err}");
+}
+
+#[test]
+fn upstream_urls() {
+    let bare: Upstream = "127.0.0.1:1080".parse().unwrap();
+    assert_eq!(bare.protocol, UpstreamProtocol::Socks5h);
+    assert!(bare.protocol.remote_dns());
+    assert_eq!(bare, Upstream::default());
+
+    let url: Upstream = "SOCKS5://bob:s3:cret@proxy.test:1081/".parse().unwrap();
+    assert_eq!(url.protocol, UpstreamProtocol::Socks5);
+    assert!(!url.protocol.remote_dns());
+    assert_eq!(url.addr, "proxy.test:1081");
+    assert_eq!(url.user.as_deref(), Some("bob"));
+    assert_eq!(url.pass.as_deref(), Some("s3:cret"));
+    assert_eq!(url.to_string(), "socks5://proxy.test:1081");
+
+    let v4: Upstream = "socks4://ident@[::1]:1080".parse().unwrap();
+    assert_eq!(v4.protocol, UpstreamProtocol::Socks4);
+    assert_eq!((v4.user.as_deref(), v4.pass), (Some("ident"), None));
+    assert_eq!(v4.addr, "[::1]:1080");
+
+    let direct: Upstream = "direct".parse().unwrap();
+    assert_eq!(direct.protocol, UpstreamProtocol::Direct);
+    assert!(!direct.protocol.is_socks5());
+    assert!(!direct.protocol.remote_dns());
+    assert_eq!(direct.to_string(), "direct");
+
+    assert!("https://proxy.test:8080".parse::<Upstream>().is_err());
+    assert!("socks5h://proxy.test".parse::<Upstream>().is_err());
+    assert!("socks5h://:1080".parse::<Upstream>().is_err());
+}
+
+#[test]
+fn udp_datagram_header() {
+    let target = TargetAddr::new("example.com", 53);
+    let packet = encode_udp_datagram(&target, b"query").unwrap();
+    assert_eq!(&packet[..5], &[0, 0, 0, 0x03, 11]);
+    let (from, payload) = decode_udp_datagram(&packet).unwrap();
+    assert_eq!(from, target);
+    assert_eq!(payload, b"query");
+
+    let v6 = TargetAddr::new("[::1]", 5353);
+    let packet = encode_udp_datagram(&v6, b"").unwrap();
+    assert_eq!(packet.len(), 3 + 1 + 16 + 2);
+    assert_eq!(decode_udp_datagram(&packet).unwrap(), (v6, &b""[..]));
+
+    let mut fragment = encode_udp_datagram(&target, b"x").unwrap();
+    fragment[2] = 1;
+    assert!(decode_udp_datagram(&fragment).is_err());
+    assert!(decode_udp_datagram(&[0, 0, 0, 0x01, 127]).is_err());
+}
+
+// SOCKS5-сервер с UDP ASSOCIATE, ретранслятор которого возвращает
+// датаграммы обратно с тем же заголовком.
+fn spawn_udp_echo_socks() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut s, _) = listener.accept().expect("accept");
+        let req = socks5_accept(&mut s, None).unwrap();
+        assert_eq!(req.command, 0x03);
+        let TargetAddr::Ip(SocketAddr::V4(client)) = req.target else {
+            panic!("expected IPv4 address, got {}", req.target);
+        };
+        let client_port = client.port();
+        assert_ne!(client_port, 0);
+
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = relay.local_addr().unwrap().port();
+        // Нулевой адрес: клиент должен подставить адрес сервера.
+        socks5_write_reply(&mut s, 0x00, &TargetAddr::new("0.0.0.0", port)).unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 1500];
+            while let Ok((n, from)) = relay.recv_from(&mut buf) {
+                assert_eq!(from.port(), client_port);
+                // Фрагмент должен быть пропущен клиентом.
+                let mut fragment = buf[..n].to_vec();
+                fragment[2] = 1;
+                relay.send_to(&fragment, from).unwrap();
+                relay.send_to(&buf[..n], from).unwrap();
+            }
+        });
+        // Ассоциация живёт, пока клиент держит управляющее соединение.
+        let mut rest = Vec::new();
+        let _ = s.read_to_end(&mut rest);
+    });
+    addr.to_string()
+}
+
+#[test]
+fn udp_associate_roundtrip() {
+    let addr = spawn_udp_echo_socks();
+    let udp = Socks5Datagram::associate(&addr, None, None).expect("associate");
+    udp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert!(udp.relay_addr().ip().is_loopback());
+
+    let target = TargetAddr::new("dns.test", 53);
+    assert_eq!(udp.send_to(b"ping", &target).unwrap(), 4);
+    let mut buf = [0u8; 16];
+    let (n, from) = udp.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"ping");
+    assert_eq!(from, target);
+    // Не поместившийся в буфер остаток отбрасывается.
+    let long = vec![b'x'; 1000];
+    udp.send_to(&long, &target).unwrap();
+    let (n, from) = udp.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], &long[..16]);
+    assert_eq!(from, target);
+}
@@ -0,0 +1,339 @@
+#![cfg(feature = "tls")]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
+
+use http2socks_proxy::{
+    Duplex, Upstream, UpstreamTlsConfig, tls_accept, tls_client_config, tls_connect,
+    tls_server_config, tls_upstream_config,
+};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+fn fixture(name: &str) -> String {
+    format!("{FIXTURES}/{name}")
+}
+
+fn echo(stream: &mut (impl Read + Write)) {
+    let mut buf = [0u8; 1024];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if stream.write_all(&buf[..n]).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// TLS-эхо сервер на сертификате для localhost/127.0.0.1.
+fn spawn_tls_echo() -> String {
+    let certs = CertificateDer::pem_file_iter(fixture("server.pem"))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let key = PrivateKeyDer::from_pem_file(fixture("server.key")).unwrap();
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+    let config = Arc::new(config);
+
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (s, _) = listener.accept().expect("accept");
+        let conn = ServerConnection::new(config).unwrap();
+        let mut tls = StreamOwned::new(conn, s);
+        echo(&mut tls);
+        tls.conn.send_close_notify();
+        let _ = tls.flush();
+    });
+    addr.to_string()
+}
+
+// Слушатель прокси с обязательным клиентским сертификатом. Возвращает
+// адрес и канал с результатом рукопожатия на стороне сервера.
+fn spawn_tls_listener() -> (String, mpsc::Receiver<bool>) {
+    let config = tls_server_config(
+        Path::new(&fixture("server.pem")),
+        Path::new(&fixture("server.key")),
+        Some(Path::new(&fixture("ca.pem"))),
+    )
+    .expect("server config");
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for _ in 0..2 {
+            let (s, _) = listener.accept().expect("accept");
+            match tls_accept(s, config.clone()) {
+                Ok(mut tls) => {
+                    tx.send(true).unwrap();
+                    echo(&mut tls);
+                }
+                Err(_) => tx.send(false).unwrap(),
+            }
+        }
+    });
+    (addr.to_string(), rx)
+}
+
+#[test]
+fn tls_origination_roundtrip() {
+    let addr = spawn_tls_echo();
+    let config = tls_client_config(Some(Path::new(&fixture("ca.pem")))).expect("CA bundle");
+    let tcp = TcpStream::connect(&addr).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut stream = tls_connect(tcp, config, "localhost").expect("handshake");
+    let mut reader = stream.try_clone().unwrap();
+
+    stream.write_all(b"ping over tls").unwrap();
+    stream.flush().unwrap();
+    let mut buf = [0u8; 13];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping over tls");
+
+    stream.shutdown_write().unwrap();
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
+
+// Обе стороны одновременно пишут больше, чем вмещают буферы сокетов:
+// писатель, ждущий места в буфере, не должен останавливать чтение.
+#[test]
+fn tls_bulk_bidirectional() {
+    const TOTAL: usize = 8 * 1024 * 1024;
+    let addr = spawn_tls_echo();
+    let config = tls_client_config(Some(Path::new(&fixture("ca.pem")))).unwrap();
+    let tcp = TcpStream::connect(&addr).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    tcp.set_write_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut writer = tls_connect(tcp, config, "localhost").expect("handshake");
+    let mut reader = writer.try_clone().unwrap();
+    let t = thread::spawn(move || {
+        let chunk = vec![0x5a; 64 * 1024];
+        for _ in 0..TOTAL / chunk.len() {
+            writer.write_all(&chunk).unwrap();
+        }
+        writer.shutdown_write().unwrap();
+    });
+    let mut received = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf).expect("echo");
+        if n == 0 {
+            break;
+        }
+        assert!(buf[..n].iter().all(|&b| b == 0x5a));
+        received += n;
+    }
+    t.join().unwrap();
+    assert_eq!(received, TOTAL);
+}
+
+#[test]
+fn tls_origination_rejects_unknown_ca() {
+    let addr = spawn_tls_echo();
+    // Встроенные корни не знают тестовый CA.
+    let config = tls_client_config(None).expect("default roots");
+    let tcp = TcpStream::connect(&addr).unwrap();
+    let err = tls_connect(tcp, config, "localhost").expect_err("must not trust test CA");
+    assert!(err.to_string().contains("UnknownIssuer"), "{err}");
+}
+
+#[test]
+fn tls_listener_requires_client_cert() {
+    let (addr, accepted) = spawn_tls_listener();
+    let ca = CertificateDer::from_pem_file(fixture("ca.pem")).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(ca).unwrap();
+    let builder =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+
+    // Без клиентского сертификата сервер обрывает рукопожатие.
+    let anonymous = Arc::new(builder.clone().with_no_client_auth());
+    let tcp = TcpStream::connect(&addr).unwrap();
+    if let Ok(mut tls) = tls_connect(tcp, anonymous, "localhost") {
+        // В TLS 1.3 отказ приходит уже после рукопожатия клиента.
+        let _ = tls.write_all(b"x");
+        let mut buf = [0u8; 1];
+        assert!(tls.read(&mut buf).is_err());
+    }
+    assert!(!accepted.recv().unwrap());
+
+    let certs = CertificateDer::pem_file_iter(fixture("client.pem"))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let key = PrivateKeyDer::from_pem_file(fixture("client.key")).unwrap();
+    let with_cert = Arc::new(builder.with_client_auth_cert(certs, key).unwrap());
+    let tcp = TcpStream::connect(&addr).unwrap();
+    let mut tls = tls_connect(tcp, with_cert, "localhost").expect("handshake with cert");
+    tls.write_all(b"hi").unwrap();
+    let mut buf = [0u8; 2];
+    tls.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hi");
+    assert!(accepted.recv().unwrap());
+}
+
+#[cfg(feature = "mitm")]
+#[test]
+fn mitm_mints_trusted_certificate() {
+    use http2socks_proxy::MitmCa;
+
+    let ca = MitmCa::load(Path::new(&fixture("ca.pem")), Path::new(&fixture("ca.key")))
+        .expect("load CA");
+    let server_config = ca.server_config("intercepted.example").expect("mint");
+    // Повторный запрос берётся из кеша.
+    assert!(Arc::ptr_eq(
+        &server_config,
+        &ca.server_config("INTERCEPTED.example").unwrap()
+    ));
+
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (s, _) = listener.accept().expect("accept");
+        if let Ok(mut tls) = tls_accept(s, server_config) {
+            echo(&mut tls);
+        }
+    });
+
+    let config = tls_client_config(Some(Path::new(&fixture("ca.pem")))).unwrap();
+    let tcp = TcpStream::connect(addr).unwrap();
+    let mut tls = tls_connect(tcp, config, "intercepted.example").expect("trusted by local CA");
+    tls.write_all(b"ok").unwrap();
+    let mut buf = [0u8; 2];
+    tls.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ok");
+}
+
+#[cfg(feature = "mitm")]
+#[test]
+fn mitm_cache_evicts_least_recently_used() {
+    use http2socks_proxy::MitmCa;
+
+    let ca = MitmCa::load(Path::new(&fixture("ca.pem")), Path::new(&fixture("ca.key")))
+        .expect("load CA")
+        .with_cache_limit(2);
+    let a = ca.server_config("a.example").unwrap();
+    let b = ca.server_config("b.example").unwrap();
+    // a использован позже b, поэтому при переполнении вытесняется b.
+    assert!(Arc::ptr_eq(&a, &ca.server_config("a.example").unwrap()));
+    ca.server_config("c.example").unwrap();
+    assert_eq!(ca.cached(), 2);
+    assert!(Arc::ptr_eq(&a, &ca.server_config("a.example").unwrap()));
+    assert!(!Arc::ptr_eq(&b, &ca.server_config("b.example").unwrap()));
+    assert_eq!(ca.cached(), 2);
+}
+
+// SOCKS5-сервер за TLS с обязательным клиентским сертификатом: проверяет
+// логин u/p и CONNECT example.com:443, затем работает как эхо.
+fn spawn_socks_over_tls(connections: usize) -> String {
+    let config = tls_server_config(
+        Path::new(&fixture("server.pem")),
+        Path::new(&fixture("server.key")),
+        Some(Path::new(&fixture("ca.pem"))),
+    )
+    .expect("server config");
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for _ in 0..connections {
+            let (s, _) = listener.accept().expect("accept");
+            let config = config.clone();
+            thread::spawn(move || {
+                let Ok(mut tls) = tls_accept(s, config) else {
+                    return;
+                };
+                let mut g = [0u8; 2];
+                tls.read_exact(&mut g).unwrap();
+                let mut methods = vec![0u8; g[1] as usize];
+                tls.read_exact(&mut methods).unwrap();
+                assert!(methods.contains(&0x02));
+                tls.write_all(&[0x05, 0x02]).unwrap();
+                let mut auth = [0u8; 6];
+                tls.read_exact(&mut auth).unwrap();
+                assert_eq!(&auth, &[0x01, 1, b'u', 2, b'p', b'w']);
+                tls.write_all(&[0x01, 0x00]).unwrap();
+                let mut req = [0u8; 5 + 11 + 2];
+                tls.read_exact(&mut req).unwrap();
+                assert_eq!(&req[..5], &[0x05, 0x01, 0x00, 0x03, 11]);
+                assert_eq!(&req[5..16], b"example.com");
+                tls.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                    .unwrap();
+                echo(&mut tls);
+            });
+        }
+    });
+    addr.to_string()
+}
+
+fn socks_tls_upstream(addr: &str, ca: bool, insecure: bool) -> Upstream {
+    let mut upstream: Upstream = format!("socks5+tls://u:pw@{addr}").parse().unwrap();
+    let ca_file = fixture("ca.pem");
+    let (cert, key) = (fixture("client.pem"), fixture("client.key"));
+    upstream.tls = Some(UpstreamTlsConfig(
+        tls_upstream_config(
+            ca.then(|| Path::new(&ca_file)),
+            Some((Path::new(&cert), Path::new(&key))),
+            insecure,
+        )
+        .unwrap(),
+    ));
+    upstream
+}
+
+#[test]
+fn socks5_over_tls_upstream() {
+    let addr = spawn_socks_over_tls(4);
+    let port = addr.rsplit_once(':').unwrap().1;
+
+    // Сертификат выписан на localhost: имя берётся из адреса сервера.
+    let upstream = socks_tls_upstream(&format!("localhost:{port}"), true, false);
+    assert_eq!(upstream.clone(), upstream);
+    assert_ne!(
+        upstream,
+        socks_tls_upstream(&format!("localhost:{port}"), true, false)
+    );
+    let mut stream = upstream.connect("example.com", 443).expect("socks5+tls");
+    stream.write_all(b"ping").unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    // Без нашего CA сертификат не проверяется.
+    let untrusted = socks_tls_upstream(&format!("localhost:{port}"), false, false);
+    assert!(untrusted.connect("example.com", 443).is_err());
+
+    // Имя SNI не совпадает с сертификатом.
+    let mut wrong_name = socks_tls_upstream(&addr, true, false);
+    wrong_name.tls_server_name = Some("proxy.example".to_owned());
+    assert!(wrong_name.connect("example.com", 443).is_err());
+
+    // С отключённой проверкой подходит любой сертификат.
+    let mut insecure = socks_tls_upstream(&addr, false, true);
+    insecure.tls_server_name = Some("proxy.example".to_owned());
+    let mut stream = insecure.connect("example.com", 443).expect("insecure");
+    stream.write_all(b"pong").unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"pong");
+}